@@ -14,6 +14,12 @@ use bevy::{
 
 fn main() {
     App::new()
+        // writes segmentation_dataset/run_<time>/000000/internal_camera_rgb.png, ...
+        .insert_resource(
+            OutputConfig::default()
+                .with_root("segmentation_dataset")
+                .with_filename_pattern("{index}/{camera}_{modality}"),
+        )
         .add_plugins((DefaultPlugins, SegmentationPlugin))
         .add_systems(Startup, setup)
         .run();
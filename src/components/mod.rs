@@ -1,11 +1,29 @@
 use bevy::{
     prelude::Deref,
-    ecs::component::Component   
+    ecs::component::Component,
+    render::{render_resource::TextureFormat, texture::BevyDefault},
 };
+use serde::{Deserialize, Serialize};
+
+pub mod stereo;
+
+pub use stereo::StereoRig;
 
 #[derive(Component)]
 pub struct SegmentationObjectParent;
 
+/// Marks the unlit mesh spawned as the segmentation twin of a `SegmentationObject`
+#[derive(Component)]
+pub struct SegmentationTwin;
+
+/// Marks the mesh spawned as the depth twin of a rendered mesh
+#[derive(Component)]
+pub struct DepthTwin;
+
+/// Marks a mesh that already has a depth twin
+#[derive(Component)]
+pub struct DepthTwinParent;
+
 #[derive(Component, Deref, PartialEq, Eq, Hash)]
 pub struct SegmentationObject(pub String);
 
@@ -24,20 +42,97 @@ impl RGBCamera {
             CameraDescription {
                 name: name.to_string(),
                 width,
-                height
+                height,
+                ..Default::default()
             }
         )
     }
+
+    /// Captures in `format`, see `CaptureFormat`
+    pub fn with_format(mut self, format: CaptureFormat) -> Self {
+        self.0.format = format;
+        self
+    }
 }
 
 #[derive(Component, Default, Deref)]
 pub struct SegmentationCamera(pub CameraDescription);
 
-#[derive(Clone)]
+/// Offscreen copy of an `RGBCamera` that renders to a window or texture view, so its view can
+/// be read back and saved
+#[derive(Component, Default, Deref)]
+pub struct CaptureCamera(pub CameraDescription);
+
+/// Camera rendering linear view depth of the depth twins, used for derived outputs like disparity
+#[derive(Component, Default, Deref)]
+pub struct DepthCamera(pub CameraDescription);
+
+/// Kind of image a camera output holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Modality {
+    Rgb,
+    Segmentation,
+    Depth,
+    Disparity,
+    /// Per frame JSON, see `FrameMetadata`
+    Metadata,
+}
+
+impl Modality {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Modality::Rgb => "rgb",
+            Modality::Segmentation => "segmentation",
+            Modality::Depth => "depth",
+            Modality::Disparity => "disparity",
+            Modality::Metadata => "metadata",
+        }
+    }
+
+    /// Name of `camera`'s output of this modality in the `CameraOutputTable`
+    pub fn output_name(&self, camera: &str) -> String {
+        match self {
+            Modality::Rgb => camera.to_string(),
+            _ => format!("{}_{}", camera, self.name()),
+        }
+    }
+}
+
+/// Pixel format of the RGB output of a camera
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum CaptureFormat {
+    /// 8 bit sRGB, saved as PNG
+    #[default]
+    Ldr,
+    /// Linear radiance in half floats, saved as EXR
+    Hdr16,
+    /// Linear radiance in floats, saved as EXR
+    Hdr32,
+}
+
+impl CaptureFormat {
+    pub fn texture_format(&self) -> TextureFormat {
+        match self {
+            CaptureFormat::Ldr => TextureFormat::bevy_default(),
+            CaptureFormat::Hdr16 => TextureFormat::Rgba16Float,
+            CaptureFormat::Hdr32 => TextureFormat::Rgba32Float,
+        }
+    }
+
+    /// HDR captures render with `hdr` on and without tonemapping
+    pub fn is_hdr(&self) -> bool {
+        *self != CaptureFormat::Ldr
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct CameraDescription {
     pub name: String,
     pub width: u32, 
     pub height: u32, 
+    /// Format of offscreen captures of an `RGBCamera`
+    pub format: CaptureFormat,
 }
 
 impl Default for CameraDescription {
@@ -47,6 +142,7 @@ impl Default for CameraDescription {
             // directory: String::from("segmentation_dataset"),
             width: 512, 
             height: 512, 
+            format: CaptureFormat::default(),
         }
     }
-}
\ No newline at end of file
+}
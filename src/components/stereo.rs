@@ -0,0 +1,112 @@
+//! Stereo Rig
+//!
+//! A rectified pair of `RGBCamera`s sharing one resolution and projection, offset along the
+//! rig's local x axis. The left view also gets a `DepthCamera` so a dense disparity map can be
+//! exported alongside the image pair.
+//!
+//! `Msaa` is global in bevy, with multisampling on the depth at silhouettes is a blend of the
+//! foreground and the background. `HeadlessSegmentationPlugin` turns it off, apps with a window
+//! need `Msaa::Off` for exact disparity.
+
+use bevy::{
+    ecs::component::Component,
+    render::texture::Image,
+};
+use image::{ImageBuffer, Luma};
+
+use crate::{
+    components::{CameraDescription, CaptureFormat, Modality},
+    error::SegmentationError,
+    materials::decode_depth,
+};
+
+/// Spawns a left and right `RGBCamera` as children of this entity, `baseline` meters apart.
+/// The entity needs a transform (e.g. `SpatialBundle`), the cameras look down its -z axis.
+#[derive(Component, Clone)]
+pub struct StereoRig {
+    pub description: CameraDescription,
+    /// Distance between the optical centers in meters
+    pub baseline: f32,
+    /// Vertical field of view of both cameras in radians
+    pub fov: f32,
+}
+
+impl Default for StereoRig {
+    fn default() -> Self {
+        StereoRig {
+            description: CameraDescription {
+                name: String::from("stereo"),
+                ..Default::default()
+            },
+            baseline: 0.12,
+            fov: std::f32::consts::FRAC_PI_4,
+        }
+    }
+}
+
+impl StereoRig {
+    pub fn new(name: &str, width: u32, height: u32, baseline: f32) -> Self {
+        StereoRig {
+            description: CameraDescription {
+                name: name.to_string(),
+                width,
+                height,
+                ..Default::default()
+            },
+            baseline,
+            ..Default::default()
+        }
+    }
+
+    /// Captures both views in `format`, see `CaptureFormat`
+    pub fn with_format(mut self, format: CaptureFormat) -> Self {
+        self.description.format = format;
+        self
+    }
+
+    pub fn left_name(&self) -> String {
+        format!("{}_left", self.description.name)
+    }
+
+    pub fn right_name(&self) -> String {
+        format!("{}_right", self.description.name)
+    }
+
+    pub fn depth_name(&self) -> String {
+        Modality::Depth.output_name(&self.left_name())
+    }
+
+    pub fn disparity_name(&self) -> String {
+        Modality::Disparity.output_name(&self.description.name)
+    }
+
+    /// Focal length of both views in pixels
+    pub fn focal_length(&self) -> f32 {
+        0.5 * self.description.height as f32 / (0.5 * self.fov).tan()
+    }
+
+    /// Horizontal disparity in pixels of a point at `depth` meters in front of the rig
+    pub fn disparity(&self, depth: f32) -> f32 {
+        if depth > 0.0 {
+            self.focal_length() * self.baseline / depth
+        } else {
+            0.0
+        }
+    }
+
+    /// Converts the left view's depth image to a disparity map in the KITTI encoding:
+    /// a 16 bit image holding `disparity * 256`, where 0 marks pixels without a surface.
+    pub fn disparity_image(&self, depth: &Image) -> Result<ImageBuffer<Luma<u16>, Vec<u16>>, SegmentationError> {
+        let disparity = decode_depth(depth)
+            .into_iter()
+            .map(|z| (self.disparity(z) * 256.0).round().min(u16::MAX as f32) as u16)
+            .collect();
+
+        ImageBuffer::from_raw(depth.width(), depth.height(), disparity)
+            .ok_or_else(|| SegmentationError::SizeMismatch {
+                camera: self.depth_name(),
+                expected: depth.width() as usize * depth.height() as usize * 16,
+                actual: depth.data.len(),
+            })
+    }
+}
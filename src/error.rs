@@ -0,0 +1,187 @@
+//! Errors
+//!
+//! Failures in the capture path are reported as `CaptureFailed` events and logged instead of
+//! panicking, so long generation jobs survive a bad frame. The `FailurePolicy` decides whether
+//! failed writes are retried and whether the app keeps running.
+
+use bevy::{
+    app::AppExit,
+    prelude::*,
+    render::{render_resource::TextureFormat, RenderApp},
+};
+use crossbeam_channel::{Receiver, Sender};
+use std::{fmt, path::PathBuf};
+
+use crate::utils::{
+    encoding::Encoding,
+    image_writer::{flush_image_writer, ImageWriter},
+};
+
+/// Collects errors from both worlds and applies the `FailurePolicy`
+pub struct CaptureErrorPlugin;
+
+impl Plugin for CaptureErrorPlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        app
+            .init_resource::<FailurePolicy>()
+            .insert_resource(RenderErrorReceiver(receiver))
+            .add_event::<CaptureFailed>()
+            .add_systems(PreUpdate, forward_render_errors)
+            .add_systems(PostUpdate, apply_failure_policy.run_if(resource_changed::<FailurePolicy>))
+            .add_systems(Last, report_capture_failures.after(flush_image_writer));
+
+        app.sub_app_mut(RenderApp)
+            .insert_resource(RenderErrorSender(sender));
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum SegmentationError {
+    /// The image behind a camera output is gone
+    MissingImage { camera: String },
+    /// The image format can not be written by the chosen encoder
+    UnsupportedFormat { path: PathBuf, format: TextureFormat, encoding: Encoding },
+    /// Image data does not match the image size
+    SizeMismatch { camera: String, expected: usize, actual: usize },
+    /// The render target of a camera was not prepared on the GPU
+    MissingGpuImage { camera_id: usize },
+    /// The GPU could not map a staging buffer
+    MapFailed { camera_id: usize },
+    /// Some outputs never delivered `frame`, so its bundle is not saved
+    IncompleteFrame { frame: u32, missing: Vec<String> },
+    Encode { path: PathBuf, message: String },
+    Io { path: PathBuf, message: String },
+    /// A job file could not be read or parsed
+    InvalidJob { path: PathBuf, message: String },
+    /// A job asks for something its spec does not allow, e.g. sharding a job without a name
+    InvalidJobSpec { message: String },
+    /// A checkpoint could not be read, or does not belong to the run being resumed
+    InvalidCheckpoint { path: PathBuf, message: String },
+}
+
+impl fmt::Display for SegmentationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SegmentationError::MissingImage { camera } => {
+                write!(f, "image of camera {} is missing", camera)
+            }
+            SegmentationError::UnsupportedFormat { path, format, encoding } => {
+                write!(f, "format {:?} of {} can not be saved as {}", format, path.display(), encoding)
+            }
+            SegmentationError::SizeMismatch { camera, expected, actual } => {
+                write!(f, "camera {} delivered {} bytes, expected {}", camera, actual, expected)
+            }
+            SegmentationError::MissingGpuImage { camera_id } => {
+                write!(f, "render target of camera {} is not on the GPU", camera_id)
+            }
+            SegmentationError::MapFailed { camera_id } => {
+                write!(f, "failed to map staging buffer of camera {}", camera_id)
+            }
+            SegmentationError::IncompleteFrame { frame, missing } => {
+                write!(f, "frame {} never arrived from {}", frame, missing.join(", "))
+            }
+            SegmentationError::Encode { path, message } => {
+                write!(f, "failed to encode {}: {}", path.display(), message)
+            }
+            SegmentationError::Io { path, message } => {
+                write!(f, "failed to write {}: {}", path.display(), message)
+            }
+            SegmentationError::InvalidJob { path, message } => {
+                write!(f, "invalid job {}: {}", path.display(), message)
+            }
+            SegmentationError::InvalidJobSpec { message } => write!(f, "invalid job: {}", message),
+            SegmentationError::InvalidCheckpoint { path, message } => {
+                write!(f, "invalid checkpoint {}: {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SegmentationError {}
+
+impl SegmentationError {
+    pub fn io(path: impl Into<PathBuf>, error: impl fmt::Display) -> Self {
+        SegmentationError::Io { path: path.into(), message: error.to_string() }
+    }
+
+    pub fn encode(path: impl Into<PathBuf>, error: impl fmt::Display) -> Self {
+        SegmentationError::Encode { path: path.into(), message: error.to_string() }
+    }
+
+    pub fn unsupported(path: impl Into<PathBuf>, format: TextureFormat, encoding: Encoding) -> Self {
+        SegmentationError::UnsupportedFormat { path: path.into(), format, encoding }
+    }
+}
+
+/// Sent for every failed step of the capture path
+#[derive(Event, Clone, Debug)]
+pub struct CaptureFailed {
+    pub error: SegmentationError,
+}
+
+/// What happens after a capture step failed
+#[derive(Resource, Clone, Copy, Debug)]
+pub enum FailurePolicy {
+    /// Report the failure and keep going
+    Continue,
+    /// Retry failed writes up to `attempts` more times, then report and keep going
+    Retry { attempts: u32 },
+    /// Report the failure and exit the app with an error
+    Exit,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> Self {
+        FailurePolicy::Retry { attempts: 2 }
+    }
+}
+
+impl FailurePolicy {
+    pub fn retries(&self) -> u32 {
+        match self {
+            FailurePolicy::Retry { attempts } => *attempts,
+            _ => 0,
+        }
+    }
+}
+
+/// Errors raised in the render world, drained into `CaptureFailed` events by the main world
+#[derive(Resource, Clone)]
+pub(crate) struct RenderErrorSender(pub Sender<SegmentationError>);
+
+#[derive(Resource)]
+struct RenderErrorReceiver(Receiver<SegmentationError>);
+
+fn forward_render_errors(
+    receiver: Res<RenderErrorReceiver>,
+    mut failures: EventWriter<CaptureFailed>,
+) {
+    while let Ok(error) = receiver.0.try_recv() {
+        failures.send(CaptureFailed { error });
+    }
+}
+
+fn apply_failure_policy(policy: Res<FailurePolicy>, mut writer: ResMut<ImageWriter>) {
+    writer.retries = policy.retries();
+}
+
+fn report_capture_failures(
+    policy: Res<FailurePolicy>,
+    mut failures: EventReader<CaptureFailed>,
+    mut app_exit: EventWriter<AppExit>,
+) {
+    let mut failed = false;
+
+    for failure in failures.read() {
+        error!("Capture failed: {}", failure.error);
+        failed = true;
+    }
+
+    if failed {
+        if let FailurePolicy::Exit = *policy {
+            app_exit.send(AppExit::error());
+        }
+    }
+}
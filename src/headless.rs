@@ -0,0 +1,143 @@
+//! Headless
+//!
+//! Dataset generation without a window or keyboard. The app is stepped by a schedule runner with
+//! a fixed simulated timestep, so every run of the same job sees the same frames, captures
+//! `frames` bundles and exits once they are written. Multisampling is off, so masks and depth
+//! stay exact at silhouettes.
+//!
+//! Cameras must render to images, e.g. from `CameraOutputTable::create_render_target`, and
+//! `DefaultPlugins` should neither open a window nor need a display server:
+//!
+//! ```ignore
+//! App::new()
+//!     .add_plugins(
+//!         DefaultPlugins
+//!             .set(HeadlessSegmentationPlugin::window_plugin())
+//!             .disable::<WinitPlugin>(),
+//!     )
+//!     .add_plugins(HeadlessSegmentationPlugin::new(100))
+//!     .run();
+//! ```
+
+use bevy::{
+    app::{AppExit, ScheduleRunnerPlugin},
+    prelude::*,
+    time::TimeUpdateStrategy,
+    window::ExitCondition,
+};
+use std::time::Duration;
+
+use crate::{
+    plugin::SegmentationPlugin,
+    resources::{CameraOutputTable, CapturePolicy, CaptureSystems, FrameBundle, RunOffset},
+};
+
+pub struct HeadlessSegmentationPlugin {
+    /// Frame bundles to capture before exiting
+    pub frames: u32,
+    /// Simulated time per frame, also the `Time<Fixed>` timestep
+    pub timestep: Duration,
+    /// Wall clock time between frames, zero runs as fast as possible
+    pub wait: Duration,
+    /// Which frames are captured
+    pub capture_policy: CapturePolicy,
+}
+
+impl Default for HeadlessSegmentationPlugin {
+    fn default() -> Self {
+        HeadlessSegmentationPlugin {
+            frames: 1,
+            timestep: Duration::from_secs_f64(1.0 / 60.0),
+            wait: Duration::ZERO,
+            capture_policy: CapturePolicy::Continuous,
+        }
+    }
+}
+
+impl HeadlessSegmentationPlugin {
+    pub fn new(frames: u32) -> Self {
+        HeadlessSegmentationPlugin {
+            frames,
+            ..default()
+        }
+    }
+
+    pub fn with_timestep(mut self, timestep: Duration) -> Self {
+        self.timestep = timestep;
+        self
+    }
+
+    pub fn with_capture_policy(mut self, capture_policy: CapturePolicy) -> Self {
+        self.capture_policy = capture_policy;
+        self
+    }
+
+    /// `WindowPlugin` without a primary window that keeps the app alive without windows
+    pub fn window_plugin() -> WindowPlugin {
+        WindowPlugin {
+            primary_window: None,
+            exit_condition: ExitCondition::DontExit,
+            close_when_requested: false,
+        }
+    }
+}
+
+impl Plugin for HeadlessSegmentationPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<SegmentationPlugin>() {
+            app.add_plugins(SegmentationPlugin);
+        }
+
+        app
+            // replaces the winit runner, there is no window to drive it
+            .add_plugins(ScheduleRunnerPlugin::run_loop(self.wait))
+            .insert_resource(TimeUpdateStrategy::ManualDuration(self.timestep))
+            .insert_resource(Time::<Fixed>::from_duration(self.timestep))
+            .insert_resource(self.capture_policy.clone())
+            // multisampling blends depth and class colors of neighbouring surfaces at
+            // silhouettes, ground truth takes every pixel from a single surface
+            .insert_resource(Msaa::Off)
+            .insert_resource(HeadlessJob {
+                frames: self.frames,
+                captured: 0,
+            })
+            .add_systems(PostUpdate, finish_headless_job.after(CaptureSystems));
+    }
+}
+
+/// Progress of the headless job
+#[derive(Resource, Clone, Debug)]
+pub struct HeadlessJob {
+    pub frames: u32,
+    pub captured: u32,
+}
+
+impl HeadlessJob {
+    pub fn is_done(&self) -> bool {
+        self.captured >= self.frames
+    }
+}
+
+// Pending writes are flushed by the `ImageWriter` on `AppExit`
+fn finish_headless_job(
+    mut job: ResMut<HeadlessJob>,
+    mut frame_bundles: EventReader<FrameBundle>,
+    image_table: Res<CameraOutputTable>,
+    offset: Res<RunOffset>,
+    mut app_exit: EventWriter<AppExit>,
+) {
+    if job.is_done() || frame_bundles.read().count() == 0 {
+        return;
+    }
+
+    // counted by capture index, a dropped frame of a shard must not be made up by capturing
+    // the first index of the next shard
+    if let Some(index) = image_table.capture_index {
+        job.captured = job.captured.max((index + 1).saturating_sub(offset.index));
+    }
+
+    if job.is_done() {
+        info!("Captured {} frames, exiting", job.captured);
+        app_exit.send(AppExit::Success);
+    }
+}
@@ -0,0 +1,103 @@
+//! Input
+//!
+//! Key bindings and the events they are translated to. Everything the keyboard does can also be
+//! driven from code by sending the events directly, which is the only way when the bindings are
+//! disabled or the app has no keyboard input at all.
+
+use bevy::prelude::*;
+
+use crate::visualization::SegmentationViewMode;
+
+/// Translates the `SegmentationKeyBindings` into events
+pub struct SegmentationInputPlugin;
+
+impl Plugin for SegmentationInputPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<SegmentationKeyBindings>()
+            .add_event::<CaptureRequest>()
+            .add_event::<ToggleSegmentationView>()
+            .add_systems(
+                PreUpdate,
+                send_input_events
+                    .run_if(resource_exists::<ButtonInput<KeyCode>>)
+                    .after(bevy::input::InputSystem),
+            );
+    }
+}
+
+/// Keys used by the plugin, `None` unbinds an action
+#[derive(Resource, Clone)]
+pub struct SegmentationKeyBindings {
+    /// Shows the segmentation view while held
+    pub show_segmentation_view: Option<KeyCode>,
+    /// Cycles through the `SegmentationViewMode`s
+    pub cycle_view_mode: Option<KeyCode>,
+    /// Sends a `CaptureRequest`
+    pub capture: Option<KeyCode>,
+}
+
+impl Default for SegmentationKeyBindings {
+    fn default() -> Self {
+        SegmentationKeyBindings {
+            show_segmentation_view: Some(KeyCode::Space),
+            cycle_view_mode: Some(KeyCode::KeyV),
+            capture: Some(KeyCode::KeyS),
+        }
+    }
+}
+
+impl SegmentationKeyBindings {
+    /// No keys at all, the plugin is only driven by events
+    pub fn disabled() -> Self {
+        SegmentationKeyBindings {
+            show_segmentation_view: None,
+            cycle_view_mode: None,
+            capture: None,
+        }
+    }
+}
+
+/// Captures and saves the current frame of every camera, whatever the `CapturePolicy`
+#[derive(Event, Clone, Copy, Debug, Default)]
+pub struct CaptureRequest;
+
+/// Changes the `SegmentationView`
+#[derive(Event, Clone, Copy, Debug)]
+pub enum ToggleSegmentationView {
+    Show,
+    Hide,
+    /// Shows a hidden view and hides a visible one
+    Toggle,
+    SetMode(SegmentationViewMode),
+    CycleMode,
+}
+
+fn send_input_events(
+    keys: Res<ButtonInput<KeyCode>>,
+    bindings: Res<SegmentationKeyBindings>,
+    mut capture_requests: EventWriter<CaptureRequest>,
+    mut view_toggles: EventWriter<ToggleSegmentationView>,
+) {
+    if let Some(key) = bindings.show_segmentation_view {
+        if keys.just_pressed(key) {
+            view_toggles.send(ToggleSegmentationView::Show);
+        }
+
+        if keys.just_released(key) {
+            view_toggles.send(ToggleSegmentationView::Hide);
+        }
+    }
+
+    if let Some(key) = bindings.cycle_view_mode {
+        if keys.just_pressed(key) {
+            view_toggles.send(ToggleSegmentationView::CycleMode);
+        }
+    }
+
+    if let Some(key) = bindings.capture {
+        if keys.just_pressed(key) {
+            capture_requests.send(CaptureRequest);
+        }
+    }
+}
@@ -0,0 +1,591 @@
+//! Job
+//!
+//! Declarative description of a generation run: the scenes, cameras and stereo rigs, lights,
+//! label rules, randomizers, capture schedule and output encodings. A `JobSpec` is read from a
+//! RON or TOML file, so a run can be versioned next to the dataset it produced, and the
+//! `JobPlugin` turns it into the resources and entities that are otherwise set up by hand.
+//!
+//! ```toml
+//! name = "boxes"
+//! seed = 7
+//! labels = [{ pattern = "Car*", label = "car" }]
+//! randomizers = [{ Pose = { labels = ["car"], translation = [1.0, 0.0, 1.0] } }, { Color = {} }]
+//!
+//! [[scenes]]
+//! path = "boxes.gltf"
+//!
+//! [[cameras]]
+//! name = "front"
+//! width = 1280
+//! height = 720
+//! translation = [3.0, 3.0, 5.0]
+//! look_at = [0.0, 0.3, 0.0]
+//!
+//! [capture]
+//! frames = 100
+//! policy = { EveryNFrames = 2 }
+//!
+//! [output]
+//! root = "dataset"
+//! encodings = { rgb = "jpeg:95" }
+//! split = { train = 0.8, val = 0.1, test = 0.1, key = { Block = 50 } }
+//! ```
+//!
+//! Scene paths are asset paths, the command line loads them relative to the job file.
+
+use bevy::{
+    core_pipeline::tonemapping::Tonemapping,
+    prelude::*,
+    render::renderer::RenderDevice,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    components::{CaptureFormat, Modality, RGBCamera, StereoRig},
+    error::SegmentationError,
+    headless::HeadlessSegmentationPlugin,
+    randomizers::{RandomizerSpec, Randomizers},
+    resources::{
+        CameraOutputTable, CapturePolicy, CaptureReadiness, Checkpoint, CheckpointConfig, ExporterState,
+        LabelRule, LabelRules, OutputConfig, RunOffset, Shard, SplitPolicy,
+    },
+    utils::encoding::Encoding,
+};
+
+#[derive(Resource, Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct JobSpec {
+    /// Name of the run directory, a timestamp without it
+    pub name: Option<String>,
+    pub seed: u64,
+    pub scenes: Vec<SceneSpec>,
+    /// A single default camera is spawned if there are neither cameras nor stereo rigs
+    pub cameras: Vec<CameraSpec>,
+    pub stereo_rigs: Vec<StereoRigSpec>,
+    /// One directional light if left out
+    pub lights: Vec<LightSpec>,
+    pub labels: Vec<LabelRule>,
+    /// Applied before every capture, see `randomizers`
+    pub randomizers: Vec<RandomizerSpec>,
+    pub capture: CaptureSpec,
+    pub output: OutputSpec,
+}
+
+impl Default for JobSpec {
+    fn default() -> Self {
+        JobSpec {
+            name: None,
+            seed: 0,
+            scenes: vec![],
+            cameras: vec![],
+            stereo_rigs: vec![],
+            lights: vec![LightSpec::Directional {
+                illuminance: light_consts::lux::AMBIENT_DAYLIGHT,
+                translation: [4.0, 8.0, 4.0],
+                look_at: [0.0, 0.0, 0.0],
+                shadows: true,
+            }],
+            labels: vec![],
+            randomizers: vec![],
+            capture: CaptureSpec::default(),
+            output: OutputSpec::default(),
+        }
+    }
+}
+
+impl JobSpec {
+    /// Reads a job from a `.toml` file, any other extension is read as RON
+    pub fn load(path: &Path) -> Result<Self, SegmentationError> {
+        let invalid = |message: String| SegmentationError::InvalidJob {
+            path: path.to_path_buf(),
+            message,
+        };
+
+        let source = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Self::from_toml(&source).map_err(|e| invalid(e.to_string())),
+            _ => Self::from_ron(&source).map_err(|e| invalid(e.to_string())),
+        }
+    }
+
+    pub fn from_ron(source: &str) -> Result<Self, ron::error::SpannedError> {
+        ron::from_str(source)
+    }
+
+    pub fn from_toml(source: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(source)
+    }
+
+    /// Where and how the captures of this job are saved
+    pub fn output_config(&self) -> OutputConfig {
+        let mut config = OutputConfig::default()
+            .with_root(&self.output.root)
+            .with_seed(self.seed)
+            .with_hdr_previews(self.output.hdr_previews)
+            .with_split(self.output.split.clone());
+
+        if let Some(name) = &self.name {
+            config = config.with_run_name(Some(name.clone()));
+        }
+        if let Some(pattern) = &self.output.filename_pattern {
+            config = config.with_filename_pattern(pattern);
+        }
+        for (modality, encoding) in self.output.encodings.iter() {
+            config = config.with_encoding(*modality, *encoding);
+        }
+
+        config
+    }
+
+    /// Runs the capture schedule of this job without a window
+    pub fn headless_plugin(&self) -> HeadlessSegmentationPlugin {
+        HeadlessSegmentationPlugin::new(self.capture.frames)
+            .with_timestep(self.capture.timestep)
+            .with_capture_policy(self.capture.policy.clone())
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SceneSpec {
+    /// glTF file, its first scene is spawned, or a bevy scene ending in `.scn.ron`
+    pub path: String,
+    #[serde(default)]
+    pub translation: [f32; 3],
+    /// Uniform scale
+    #[serde(default = "one")]
+    pub scale: f32,
+}
+
+impl SceneSpec {
+    pub fn new(path: &str) -> Self {
+        SceneSpec {
+            path: path.to_string(),
+            scale: 1.0,
+            ..default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CameraSpec {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub translation: [f32; 3],
+    pub look_at: [f32; 3],
+    /// Vertical field of view in radians
+    #[serde(default)]
+    pub fov: Option<f32>,
+    #[serde(default)]
+    pub format: CaptureFormat,
+}
+
+impl Default for CameraSpec {
+    fn default() -> Self {
+        CameraSpec {
+            name: String::from("camera"),
+            width: 1280,
+            height: 720,
+            translation: [0.0, 1.0, 3.0],
+            look_at: [0.0, 0.0, 0.0],
+            fov: None,
+            format: CaptureFormat::Ldr,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StereoRigSpec {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    /// Distance between the optical centers in meters
+    pub baseline: f32,
+    pub translation: [f32; 3],
+    pub look_at: [f32; 3],
+    /// Vertical field of view in radians
+    #[serde(default)]
+    pub fov: Option<f32>,
+    #[serde(default)]
+    pub format: CaptureFormat,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum LightSpec {
+    Directional {
+        /// Lux
+        illuminance: f32,
+        translation: [f32; 3],
+        look_at: [f32; 3],
+        #[serde(default)]
+        shadows: bool,
+    },
+    Point {
+        /// Lumens
+        intensity: f32,
+        translation: [f32; 3],
+        #[serde(default)]
+        shadows: bool,
+    },
+    Spot {
+        /// Lumens
+        intensity: f32,
+        translation: [f32; 3],
+        look_at: [f32; 3],
+        /// Half angle of the cone in radians
+        #[serde(default)]
+        angle: Option<f32>,
+        #[serde(default)]
+        shadows: bool,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CaptureSpec {
+    /// Frame bundles to capture in headless runs
+    pub frames: u32,
+    /// Simulated seconds per frame
+    #[serde(with = "seconds")]
+    pub timestep: Duration,
+    pub policy: CapturePolicy,
+    /// Captures between two checkpoints, see `Checkpoint`
+    pub checkpoint_interval: u32,
+}
+
+impl Default for CaptureSpec {
+    fn default() -> Self {
+        CaptureSpec {
+            frames: 1,
+            timestep: Duration::from_secs_f64(1.0 / 60.0),
+            policy: CapturePolicy::Continuous,
+            checkpoint_interval: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OutputSpec {
+    /// Directory the run is written under
+    pub root: PathBuf,
+    /// See `OutputConfig`
+    pub filename_pattern: Option<String>,
+    /// Encoding per modality, e.g. `{rgb: "jpeg:95", depth: "npy"}`. EXR needs an HDR camera
+    /// format.
+    pub encodings: HashMap<Modality, Encoding>,
+    /// Also save a tonemapped PNG next to every EXR
+    pub hdr_previews: bool,
+    /// Train, val and test splits, e.g. `(train: 0.8, val: 0.1, test: 0.1, key: Block(50))`
+    pub split: Option<SplitPolicy>,
+}
+
+impl Default for OutputSpec {
+    fn default() -> Self {
+        OutputSpec {
+            root: PathBuf::from("segmentation_dataset"),
+            filename_pattern: None,
+            encodings: HashMap::new(),
+            hdr_previews: false,
+            split: None,
+        }
+    }
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+/// `Duration`s as float seconds in job files
+pub(crate) mod seconds {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Duration::try_from_secs_f64(f64::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// Inserts the resources of a `JobSpec` and spawns its scenes, cameras and lights on startup.
+/// Add `JobPlugin::headless_plugin` to run it without a window.
+///
+/// Under a policy with a fixed number of steps per capture the run saves a `Checkpoint`, and can
+/// be split into shards or resumed from one.
+pub struct JobPlugin {
+    pub spec: JobSpec,
+    pub shard: Option<Shard>,
+    pub checkpoint: Option<Checkpoint>,
+}
+
+impl JobPlugin {
+    pub fn new(spec: JobSpec) -> Self {
+        JobPlugin { spec, shard: None, checkpoint: None }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, SegmentationError> {
+        JobSpec::load(path).map(JobPlugin::new)
+    }
+
+    /// Captures only the indices of `shard`. Shards of a job write into the same run directory,
+    /// so the job needs a name.
+    pub fn with_shard(mut self, shard: Shard) -> Result<Self, SegmentationError> {
+        self.shard = Some(shard);
+        self.steps_per_capture()?;
+        if self.spec.name.is_none() {
+            return Err(invalid_spec("shards of a job need its name"));
+        }
+        Ok(self)
+    }
+
+    /// Continues from the checkpoint of the run, from the start if it has none yet. The run is
+    /// found by the name of the job.
+    pub fn resume(mut self) -> Result<Self, SegmentationError> {
+        self.steps_per_capture()?;
+        if self.spec.name.is_none() {
+            return Err(invalid_spec("only named jobs can be resumed"));
+        }
+
+        let path = self.checkpoint_path();
+        let Some(checkpoint) = Checkpoint::load(&path)? else {
+            return Ok(self);
+        };
+        if checkpoint.seed != self.spec.seed || checkpoint.shard != self.shard {
+            return Err(self.invalid_checkpoint(format!(
+                "saved by seed {} and shard {:?}, resuming seed {} and shard {:?}",
+                checkpoint.seed, checkpoint.shard, self.spec.seed, self.shard,
+            )));
+        }
+
+        self.checkpoint = Some(checkpoint);
+        Ok(self)
+    }
+
+    /// Index and step of the first capture of this process
+    pub fn offset(&self) -> RunOffset {
+        if let Some(checkpoint) = &self.checkpoint {
+            return RunOffset { index: checkpoint.next_index, step: checkpoint.next_step };
+        }
+
+        let index = self.shard.map_or(0, |shard| shard.range(self.spec.capture.frames).start);
+        let steps_per_capture = self.spec.capture.policy.steps_per_capture().unwrap_or(1);
+        RunOffset { index, step: index * steps_per_capture }
+    }
+
+    /// Frame bundles this process still has to capture
+    pub fn remaining_frames(&self) -> u32 {
+        self.end_index().saturating_sub(self.offset().index)
+    }
+
+    /// Runs the remaining captures of this job without a window
+    pub fn headless_plugin(&self) -> HeadlessSegmentationPlugin {
+        HeadlessSegmentationPlugin {
+            frames: self.remaining_frames(),
+            ..self.spec.headless_plugin()
+        }
+    }
+
+    fn end_index(&self) -> u32 {
+        let frames = self.spec.capture.frames;
+        self.shard.map_or(frames, |shard| shard.range(frames).end)
+    }
+
+    fn checkpoint_path(&self) -> PathBuf {
+        Checkpoint::path(&self.spec.output_config(), self.shard)
+    }
+
+    fn steps_per_capture(&self) -> Result<u32, SegmentationError> {
+        self.spec.capture.policy.steps_per_capture().ok_or_else(|| {
+            invalid_spec(&format!(
+                "{:?} has no fixed steps per capture, only EveryNFrames and Continuous can be split or resumed",
+                self.spec.capture.policy,
+            ))
+        })
+    }
+
+    fn invalid_checkpoint(&self, message: String) -> SegmentationError {
+        SegmentationError::InvalidCheckpoint { path: self.checkpoint_path(), message }
+    }
+}
+
+fn invalid_spec(message: &str) -> SegmentationError {
+    SegmentationError::InvalidJobSpec { message: message.to_string() }
+}
+
+impl Plugin for JobPlugin {
+    fn build(&self, app: &mut App) {
+        // unnamed jobs get a run directory from the clock, the checkpoint has to land in the same
+        let output = self.spec.output_config();
+        let checkpoint_path = Checkpoint::path(&output, self.shard);
+
+        app
+            .insert_resource(output)
+            .insert_resource(LabelRules(self.spec.labels.clone()))
+            .insert_resource(self.spec.capture.policy.clone())
+            .insert_resource(self.spec.clone())
+            .insert_resource(self.offset())
+            .add_systems(Startup, (spawn_job_scenes, spawn_job_cameras));
+
+        if let Ok(steps_per_capture) = self.steps_per_capture() {
+            app.insert_resource(CheckpointConfig {
+                path: checkpoint_path,
+                shard: self.shard,
+                interval: self.spec.capture.checkpoint_interval,
+                steps_per_capture,
+                end_index: self.end_index(),
+            });
+        }
+        if let Some(checkpoint) = &self.checkpoint {
+            app.insert_resource(ExporterState(checkpoint.exporters.clone()));
+        }
+
+        let mut randomizers = app.world_mut().get_resource_or_insert_with(Randomizers::default);
+        for randomizer in self.spec.randomizers.iter() {
+            randomizer.add_to(&mut randomizers);
+        }
+        // once before every capture
+        randomizers.interval = self.spec.capture.policy.steps_per_capture().unwrap_or(1);
+    }
+}
+
+fn spawn_job_scenes(
+    mut commands: Commands,
+    job: Res<JobSpec>,
+    asset_server: Res<AssetServer>,
+    mut readiness: ResMut<CaptureReadiness>,
+) {
+    for scene in job.scenes.iter() {
+        let transform = Transform::from_translation(Vec3::from(scene.translation))
+            .with_scale(Vec3::splat(scene.scale));
+
+        if scene.path.ends_with(".scn.ron") {
+            let handle = asset_server.load::<DynamicScene>(scene.path.clone());
+            // only `Handle<Scene>`s are tracked on their own
+            readiness.track(&handle);
+            commands.spawn(DynamicSceneBundle {
+                scene: handle,
+                transform,
+                ..default()
+            });
+        } else {
+            commands.spawn(SceneBundle {
+                scene: asset_server.load(format!("{}#Scene0", scene.path)),
+                transform,
+                ..default()
+            });
+        }
+    }
+
+    for light in job.lights.iter() {
+        match light {
+            LightSpec::Directional { illuminance, translation, look_at, shadows } => {
+                commands.spawn(DirectionalLightBundle {
+                    directional_light: DirectionalLight {
+                        illuminance: *illuminance,
+                        shadows_enabled: *shadows,
+                        ..default()
+                    },
+                    transform: Transform::from_translation(Vec3::from(*translation))
+                        .looking_at(Vec3::from(*look_at), Vec3::Y),
+                    ..default()
+                });
+            }
+            LightSpec::Point { intensity, translation, shadows } => {
+                commands.spawn(PointLightBundle {
+                    point_light: PointLight {
+                        intensity: *intensity,
+                        shadows_enabled: *shadows,
+                        ..default()
+                    },
+                    transform: Transform::from_translation(Vec3::from(*translation)),
+                    ..default()
+                });
+            }
+            LightSpec::Spot { intensity, translation, look_at, angle, shadows } => {
+                let spot_light = SpotLight::default();
+                let outer_angle = angle.unwrap_or(spot_light.outer_angle);
+                commands.spawn(SpotLightBundle {
+                    spot_light: SpotLight {
+                        intensity: *intensity,
+                        shadows_enabled: *shadows,
+                        outer_angle,
+                        inner_angle: spot_light.inner_angle.min(outer_angle),
+                        ..spot_light
+                    },
+                    transform: Transform::from_translation(Vec3::from(*translation))
+                        .looking_at(Vec3::from(*look_at), Vec3::Y),
+                    ..default()
+                });
+            }
+        }
+    }
+}
+
+fn spawn_job_cameras(
+    mut commands: Commands,
+    job: Res<JobSpec>,
+    mut image_table: ResMut<CameraOutputTable>,
+    render_device: Res<RenderDevice>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let default_cameras = [CameraSpec::default()];
+    let cameras = if job.cameras.is_empty() && job.stereo_rigs.is_empty() {
+        info!("Job has no cameras, using the default camera");
+        &default_cameras[..]
+    } else {
+        &job.cameras[..]
+    };
+
+    for camera in cameras {
+        let format = camera.format;
+        let target = image_table.create_output_target(
+            &camera.name,
+            Modality::Rgb,
+            camera.width,
+            camera.height,
+            format.texture_format(),
+            &mut commands,
+            &mut images,
+            &render_device,
+        );
+
+        commands.spawn((Camera3dBundle {
+            camera: Camera {
+                target,
+                hdr: format.is_hdr(),
+                ..default()
+            },
+            projection: Projection::Perspective(PerspectiveProjection {
+                fov: camera.fov.unwrap_or(PerspectiveProjection::default().fov),
+                ..default()
+            }),
+            transform: Transform::from_translation(Vec3::from(camera.translation))
+                .looking_at(Vec3::from(camera.look_at), Vec3::Y),
+            // HDR captures keep linear radiance
+            tonemapping: if format.is_hdr() { Tonemapping::None } else { Tonemapping::default() },
+            ..default()
+        }, RGBCamera::new(&camera.name, camera.width, camera.height).with_format(format)));
+    }
+
+    for rig in job.stereo_rigs.iter() {
+        let mut stereo_rig = StereoRig::new(&rig.name, rig.width, rig.height, rig.baseline)
+            .with_format(rig.format);
+        if let Some(fov) = rig.fov {
+            stereo_rig.fov = fov;
+        }
+
+        commands.spawn((SpatialBundle {
+            transform: Transform::from_translation(Vec3::from(rig.translation))
+                .looking_at(Vec3::from(rig.look_at), Vec3::Y),
+            ..default()
+        }, stereo_rig));
+    }
+}
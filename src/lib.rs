@@ -1,11 +1,23 @@
 // Define Modules
 pub mod components;
+pub mod error;
+pub mod headless;
+pub mod input;
+pub mod job;
+pub mod materials;
 pub mod plugin;
+pub mod randomizers;
 pub mod resources;
 pub mod utils;
+pub mod visualization;
 
 // Re-export user interface types
-pub use components::{SegmentationObject, SegmentationCamera, RGBCamera};
+pub use components::{SegmentationObject, SegmentationCamera, RGBCamera, StereoRig};
 // pub use camera::SegmentationCameraBundle;
 
+pub use error::{CaptureFailed, FailurePolicy, SegmentationError};
+pub use headless::HeadlessSegmentationPlugin;
+pub use job::{JobPlugin, JobSpec};
+pub use input::{CaptureRequest, SegmentationKeyBindings, ToggleSegmentationView};
 pub use plugin::SegmentationPlugin;
+pub use randomizers::{Randomizer, RandomizerAppExt};
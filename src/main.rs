@@ -0,0 +1,208 @@
+//! Headless dataset generator
+//!
+//! Renders a scene from a set of cameras without a window and saves `--frames` frame bundles:
+//!
+//! ```text
+//! bevy_image_segmentation --scene assets/models/FlightHelmet/FlightHelmet.gltf \
+//!     --labels labels.ron --cameras cameras.ron --frames 100 --seed 7 --format jpeg:95
+//! ```
+//!
+//! A whole run can also be described by a job file, see `JobSpec`, the other options override
+//! its values:
+//!
+//! ```text
+//! bevy_image_segmentation --job job.toml --seed 8
+//! ```
+//!
+//! Runs with a name save checkpoints, `--resume` continues one where it stopped. `--shard i/N`
+//! captures the i-th of N disjoint index ranges, the shards of a run can render on different
+//! machines and their run directories are merged by copying them into one:
+//!
+//! ```text
+//! bevy_image_segmentation --job job.toml --name boxes --shard 0/2 --resume
+//! ```
+//!
+//! `--labels` holds a RON list of `LabelRule`s, e.g. `[(pattern: "Lenses*", label: "lens")]`,
+//! `--cameras` a RON list of `CameraSpec`s, e.g.
+//! `[(name: "front", width: 1280, height: 720, translation: (0, 1, 3), look_at: (0, 0, 0))]`.
+
+use bevy::prelude::*;
+use clap::Parser;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use bevy_image_segmentation::{
+    components::{CaptureFormat, Modality},
+    error::SegmentationError,
+    job::{CameraSpec, SceneSpec},
+    resources::{LabelRule, Shard},
+    utils::encoding::Encoding,
+    HeadlessSegmentationPlugin,
+    JobPlugin,
+    JobSpec,
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Renders a segmentation dataset of a scene without a window")]
+struct Args {
+    /// RON or TOML job file, scene paths in it are relative to the file
+    #[arg(long)]
+    job: Option<PathBuf>,
+    /// glTF scene, or a bevy scene ending in `.scn.ron`, replaces the scenes of the job
+    #[arg(long)]
+    scene: Option<PathBuf>,
+    /// RON list of label rules matched against mesh names
+    #[arg(long)]
+    labels: Option<PathBuf>,
+    /// RON list of cameras, a single default camera if neither the job nor this has any
+    #[arg(long)]
+    cameras: Option<PathBuf>,
+    /// Frame bundles to capture
+    #[arg(long)]
+    frames: Option<u32>,
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Name of the run directory, needed to resume or shard a run
+    #[arg(long)]
+    name: Option<String>,
+    /// Captures the i-th of N parts of the frames, counted from 0
+    #[arg(long)]
+    shard: Option<Shard>,
+    /// Continues from the checkpoint of the run
+    #[arg(long)]
+    resume: bool,
+    /// Directory the run is written under
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// RGB encoding: png[:compression], jpeg[:quality], webp, exr, npy or zstd[:level].
+    /// exr captures half float HDR.
+    #[arg(long)]
+    format: Option<Encoding>,
+}
+
+fn main() -> AppExit {
+    let args = Args::parse();
+
+    let (spec, asset_folder) = job_from_args(&args).unwrap_or_else(|message| {
+        eprintln!("error: {}", message);
+        process::exit(2)
+    });
+
+    if spec.scenes.is_empty() {
+        eprintln!("error: no scene, pass --scene or a job with scenes");
+        process::exit(2)
+    }
+
+    let job = job_plugin(spec, &args).unwrap_or_else(|error| {
+        eprintln!("error: {}", error);
+        process::exit(2)
+    });
+
+    // a headless run of zero frames would never finish
+    if job.remaining_frames() == 0 {
+        println!("nothing left to capture");
+        return AppExit::Success;
+    }
+
+    App::new()
+        .add_plugins(
+            DefaultPlugins
+                .set(HeadlessSegmentationPlugin::window_plugin())
+                .set(AssetPlugin {
+                    file_path: asset_folder,
+                    ..default()
+                }),
+        )
+        .add_plugins(job.headless_plugin())
+        .add_plugins(job)
+        .run()
+}
+
+fn job_plugin(spec: JobSpec, args: &Args) -> Result<JobPlugin, SegmentationError> {
+    let mut job = JobPlugin::new(spec);
+    if let Some(shard) = args.shard {
+        job = job.with_shard(shard)?;
+    }
+    if args.resume {
+        job = job.resume()?;
+    }
+    Ok(job)
+}
+
+/// The job with the command line applied, and the folder its scenes are loaded from
+fn job_from_args(args: &Args) -> Result<(JobSpec, String), String> {
+    let (mut spec, mut asset_folder) = match &args.job {
+        Some(path) => {
+            let spec = JobSpec::load(path).map_err(|e| e.to_string())?;
+            (spec, absolute_parent(path)?)
+        }
+        None => (JobSpec::default(), absolute_parent(Path::new("."))?),
+    };
+
+    // scenes find their own textures and buffers next to them
+    if let Some(scene) = &args.scene {
+        let file_name = scene
+            .file_name()
+            .ok_or_else(|| format!("{} is not a scene file", scene.display()))?;
+        spec.scenes = vec![SceneSpec::new(&file_name.to_string_lossy())];
+        asset_folder = absolute_parent(scene)?;
+    }
+
+    if let Some(path) = &args.labels {
+        spec.labels = read_ron::<Vec<LabelRule>>(path)?;
+    }
+    if let Some(path) = &args.cameras {
+        spec.cameras = read_ron::<Vec<CameraSpec>>(path)?;
+    }
+    if let Some(frames) = args.frames {
+        spec.capture.frames = frames;
+    }
+    if let Some(seed) = args.seed {
+        spec.seed = seed;
+    }
+    if let Some(name) = &args.name {
+        spec.name = Some(name.clone());
+    }
+    if let Some(output) = &args.output {
+        spec.output.root = output.clone();
+    }
+    if let Some(encoding) = args.format {
+        spec.output.encodings.insert(Modality::Rgb, encoding);
+        // EXR keeps linear radiance, the cameras have to render it
+        if encoding == Encoding::Exr {
+            let formats = spec
+                .cameras
+                .iter_mut()
+                .map(|camera| &mut camera.format)
+                .chain(spec.stereo_rigs.iter_mut().map(|rig| &mut rig.format));
+            for format in formats.filter(|format| !format.is_hdr()) {
+                *format = CaptureFormat::Hdr16;
+            }
+            if spec.cameras.is_empty() && spec.stereo_rigs.is_empty() {
+                spec.cameras.push(CameraSpec {
+                    format: CaptureFormat::Hdr16,
+                    ..default()
+                });
+            }
+        }
+    }
+
+    Ok((spec, asset_folder))
+}
+
+fn read_ron<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    ron::from_str(&source).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Absolute folder of `path`, the asset server resolves relative folders against the executable
+fn absolute_parent(path: &Path) -> Result<String, String> {
+    let path = fs::canonicalize(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let folder = if path.is_dir() { Some(path.as_path()) } else { path.parent() };
+    folder
+        .map(|folder| folder.to_string_lossy().into_owned())
+        .ok_or_else(|| format!("{} has no parent folder", path.display()))
+}
@@ -0,0 +1,16 @@
+#import bevy_pbr::{
+    forward_io::VertexOutput,
+    mesh_view_bindings::view,
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    // cameras look down -z in view space
+    let depth = -(view.view_from_world * in.world_position).z;
+
+    // keep the bits lost to the half float main pass in a second channel
+    let coarse = unpack2x16float(pack2x16float(vec2<f32>(depth, 0.0))).x;
+    let residual = depth - coarse;
+
+    return vec4<f32>(coarse, residual, 0.0, 1.0);
+}
@@ -0,0 +1,52 @@
+//! Materials
+//!
+//! Materials used by the twin meshes the plugin spawns for its auxiliary cameras.
+
+use bevy::{
+    asset::load_internal_asset,
+    prelude::*,
+    render::render_resource::{AsBindGroup, ShaderRef, TextureFormat},
+};
+
+pub const DEPTH_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(0x5e6d_1c3a_2f4b_4e9c_8d71_0a3b_c2e4_f519);
+
+/// Format of the render targets used by `DepthCamera`s
+pub const DEPTH_TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba32Float;
+
+/// Registers the twin materials and their embedded shaders
+pub struct SegmentationMaterialsPlugin;
+
+impl Plugin for SegmentationMaterialsPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(app, DEPTH_SHADER_HANDLE, "depth.wgsl", Shader::from_wgsl);
+
+        app.add_plugins(MaterialPlugin::<DepthMaterial>::default());
+    }
+}
+
+/// Writes linear view space depth (meters along the camera's forward axis)
+///
+/// The main pass of an HDR camera is half precision, so the shader splits the depth into the
+/// nearest half float (red) and the remainder (green). `decode_depth` sums them back up.
+#[derive(Asset, TypePath, AsBindGroup, Clone, Default)]
+pub struct DepthMaterial {}
+
+impl Material for DepthMaterial {
+    fn fragment_shader() -> ShaderRef {
+        DEPTH_SHADER_HANDLE.into()
+    }
+}
+
+/// Reads the per pixel depth out of a `DEPTH_TEXTURE_FORMAT` image, 0.0 where nothing was drawn
+pub fn decode_depth(image: &Image) -> Vec<f32> {
+    image
+        .data
+        .chunks_exact(16)
+        .map(|pixel| {
+            let coarse = f32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+            let residual = f32::from_le_bytes([pixel[4], pixel[5], pixel[6], pixel[7]]);
+            coarse + residual
+        })
+        .collect()
+}
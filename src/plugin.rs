@@ -1,10 +1,13 @@
 
 use bevy::{
     // app::ScheduleRunnerPlugin,
+    core_pipeline::{core_3d::Camera3dBundle, tonemapping::{DebandDither, Tonemapping}},
     prelude::*,
     render::{
-        camera::RenderTarget,
+        camera::{CameraUpdateSystem, RenderTarget},
+        render_resource::TextureFormat,
         renderer::RenderDevice,
+        texture::BevyDefault,
         view::RenderLayers,
     },
 };
@@ -12,7 +15,11 @@ use bevy::{
 // use std::time::Duration;
 use crate::{
     components::*,
+    input::SegmentationInputPlugin,
+    materials::*,
+    randomizers::RandomizerPlugin,
     resources::*,
+    visualization::SegmentationViewPlugin,
 };
 
 pub struct SegmentationPlugin;
@@ -25,11 +32,27 @@ impl Plugin for SegmentationPlugin {
             .init_resource::<SegmentationDataTable>()
             .init_resource::<CameraOutputTable>()
             // .insert_resource(ClearColor(Color::srgb_u8(0, 0, 0)))
-            .add_systems(PostStartup, (spawn_segmentation_cameras, spawn_segmentation_materials))
+            .add_plugins((SegmentationMaterialsPlugin, RandomizerPlugin))
+            .init_resource::<LabelRules>()
             .add_systems(
-                Update,
-                toggle_segmentation_view.run_if(resource_changed::<ButtonInput<KeyCode>>),
+                PostStartup,
+                (
+                    spawn_stereo_rigs,
+                    spawn_segmentation_cameras,
+                ).chain(),
+            )
+            .add_systems(Update, label_rules::apply_label_rules)
+            // scenes keep spawning meshes after startup, twins are added as they appear
+            .add_systems(
+                PostUpdate,
+                (
+                    spawn_segmentation_materials,
+                    spawn_depth_materials,
+                ).chain().before(CaptureSystems),
             )
+            .add_plugins((SegmentationInputPlugin, SegmentationViewPlugin))
+            // twins have to match before their projections are recomputed
+            .add_systems(PostUpdate, sync_twin_cameras.before(CameraUpdateSystem))
             // headless frame capture
             .add_plugins(InternalCameraOutput);
             // .add_plugins(ScheduleRunnerPlugin::run_loop(
@@ -39,51 +62,237 @@ impl Plugin for SegmentationPlugin {
     }
 }
 
+/// Spawns the left/right cameras of each `StereoRig`, plus a depth camera for the left view
+fn spawn_stereo_rigs(
+    rig_query: Query<(Entity, &StereoRig)>,
+    mut commands: Commands,
+    mut image_table: ResMut<CameraOutputTable>,
+    render_device: Res<RenderDevice>,
+    mut images: ResMut<Assets<Image>>,
+) {
+
+    for (entity, rig) in rig_query.iter() {
+
+        let width = rig.description.width;
+        let height = rig.description.height;
+        // Identical projections and parallel optical axes keep the pair rectified
+        let projection = Projection::Perspective(PerspectiveProjection {
+            fov: rig.fov,
+            ..default()
+        });
+
+        let depth_target = image_table.create_output_target(
+            &rig.left_name(),
+            Modality::Depth,
+            width,
+            height,
+            DEPTH_TEXTURE_FORMAT,
+            &mut commands,
+            &mut images,
+            &render_device
+        );
+
+        let depth_camera = commands.spawn((Camera3dBundle {
+            camera: Camera {
+                order: 2,
+                target: depth_target,
+                // half float main pass, see `DepthMaterial`
+                hdr: true,
+                clear_color: ClearColorConfig::Custom(Color::NONE),
+                ..default()
+            },
+            projection: projection.clone(),
+            tonemapping: Tonemapping::None,
+            deband_dither: DebandDither::Disabled,
+            ..default()
+        }, DepthCamera(CameraDescription {
+            name: rig.depth_name(),
+            width,
+            height,
+            ..default()
+        }), RenderLayers::layer(2))).id();
+
+        let format = rig.description.format;
+
+        for (name, offset) in [(rig.left_name(), -0.5), (rig.right_name(), 0.5)] {
+
+            let target = image_table.create_output_target(
+                &name,
+                Modality::Rgb,
+                width,
+                height,
+                format.texture_format(),
+                &mut commands,
+                &mut images,
+                &render_device
+            );
+
+            info!("Spawning Stereo Camera {}", name);
+
+            let camera = commands.spawn((Camera3dBundle {
+                camera: Camera {
+                    target,
+                    hdr: format.is_hdr(),
+                    ..default()
+                },
+                projection: projection.clone(),
+                transform: Transform::from_xyz(offset * rig.baseline, 0.0, 0.0),
+                tonemapping: capture_tonemapping(format),
+                ..default()
+            }, RGBCamera::new(&name, width, height).with_format(format))).id();
+
+            if name == rig.left_name() {
+                commands.entity(camera).add_child(depth_camera);
+            }
+
+            commands.entity(entity).add_child(camera);
+        }
+    }
+}
+
+/// HDR captures keep linear radiance, so they skip tonemapping
+fn capture_tonemapping(format: CaptureFormat) -> Tonemapping {
+    if format.is_hdr() {
+        Tonemapping::None
+    } else {
+        Tonemapping::default()
+    }
+}
+
+/// Copies the settings a twin camera needs to line up with its RGB camera. Depth cameras and
+/// HDR captures always render hdr, so only the other twins follow `hdr`.
+fn mirror_camera(source: &Camera, twin: &mut Camera, mirror_hdr: bool) {
+    // a viewport is in target pixels, it only carries over between the same kind of target
+    if std::mem::discriminant(&source.target) == std::mem::discriminant(&twin.target) {
+        twin.viewport.clone_from(&source.viewport);
+    }
+
+    if mirror_hdr {
+        twin.hdr = source.hdr;
+        // render right after the source, so a shared window target shows the mask on top
+        twin.order = source.order + 1;
+    }
+}
+
+fn segmentation_camera_bundle(
+    source: &Camera,
+    projection: Option<&Projection>,
+    target: RenderTarget,
+    is_active: bool,
+    camera_description: CameraDescription,
+) -> impl Bundle {
+    let mut camera = Camera {
+        target,
+        is_active,
+        clear_color: ClearColorConfig::Custom(Color::srgb_u8(0, 0, 0)),
+        ..default()
+    };
+    mirror_camera(source, &mut camera, true);
+
+    // masks hold the exact class colors of the label table, nothing may shift them
+    (Camera3dBundle {
+        camera,
+        projection: projection.cloned().unwrap_or_default(),
+        tonemapping: Tonemapping::None,
+        deband_dither: DebandDither::Disabled,
+        ..default()
+    }, SegmentationCamera(camera_description), RenderLayers::layer(1))
+}
+
+/// Spawns the segmentation twins of every `RGBCamera`. Cameras that do not render to an image
+/// (windows, texture views) also get an offscreen `CaptureCamera`, so their view can be saved.
 fn spawn_segmentation_cameras(
-    camera_query: Query<(Entity, &Camera, &RGBCamera)>,
+    camera_query: Query<(Entity, &Camera, Option<&Projection>, &RGBCamera)>,
     mut commands: Commands,
     mut image_table: ResMut<CameraOutputTable>,
     render_device: Res<RenderDevice>,
     mut images: ResMut<Assets<Image>>,
 ) {
 
-    for (entity, camera, camera_description) in camera_query.iter() {
+    for (entity, camera, projection, camera_description) in camera_query.iter() {
 
         let mut segmentation_camera_description = camera_description.0.clone();
         segmentation_camera_description.name += "_segmentation";
-        
-        let (target, is_active) = match camera.target {
-            RenderTarget::Image(_) => {
-                (
-                    image_table.create_render_target(
-                        segmentation_camera_description.name.clone(),
-                        segmentation_camera_description.width,
-                        segmentation_camera_description.height,
-                        &mut commands,
-                        &mut images,
-                        &render_device
-                    ), true
-                )
+
+        let offscreen_capture = match camera.target {
+            RenderTarget::Image(_) => false,
+            RenderTarget::Window(window) => {
+                // live view of the mask, shown by the `SegmentationViewPlugin`
+                let window_twin = segmentation_camera_bundle(
+                    camera,
+                    projection,
+                    RenderTarget::Window(window),
+                    false,
+                    segmentation_camera_description.clone(),
+                );
+                commands.entity(entity).with_children(|parent| {
+                    parent.spawn(window_twin);
+                });
+                true
+            }
+            RenderTarget::TextureView(_) => {
+                info!("Camera {} renders to a texture view, capturing it offscreen", camera_description.name);
+                true
             }
-            RenderTarget::Window(window) => (RenderTarget::Window(window), false),
-            _ => unimplemented!(),
         };
 
+        let target = image_table.create_output_target(
+            &camera_description.name,
+            Modality::Segmentation,
+            segmentation_camera_description.width,
+            segmentation_camera_description.height,
+            TextureFormat::bevy_default(),
+            &mut commands,
+            &mut images,
+            &render_device
+        );
+
         info!("Spawning Camera {}", segmentation_camera_description.name);
-        
+
+        let twin = segmentation_camera_bundle(
+            camera,
+            projection,
+            target,
+            true,
+            segmentation_camera_description,
+        );
         commands.entity(entity).with_children(|parent| {
+            parent.spawn(twin);
+        });
 
-            parent.spawn((Camera3dBundle {
-                camera: Camera {
-                    order: 1,
-                    target: target,
-                    is_active: is_active,
-                    clear_color: ClearColorConfig::Custom(Color::srgb_u8(0, 0, 0)),
-                    ..default()
-                },
+        if offscreen_capture {
+            let format = camera_description.format;
+            let target = image_table.create_output_target(
+                &camera_description.name,
+                Modality::Rgb,
+                camera_description.width,
+                camera_description.height,
+                format.texture_format(),
+                &mut commands,
+                &mut images,
+                &render_device
+            );
+
+            let mut capture_camera = Camera {
+                target,
+                clear_color: camera.clear_color,
+                hdr: format.is_hdr(),
                 ..default()
-            }, SegmentationCamera(segmentation_camera_description), RenderLayers::layer(1)));
-        });
+            };
+            // HDR captures always render hdr
+            mirror_camera(camera, &mut capture_camera, !format.is_hdr());
+
+            info!("Spawning Capture Camera {}", camera_description.name);
+
+            commands.entity(entity).with_children(|parent| {
+                parent.spawn((Camera3dBundle {
+                    camera: capture_camera,
+                    projection: projection.cloned().unwrap_or_default(),
+                    tonemapping: capture_tonemapping(format),
+                    ..default()
+                }, CaptureCamera(camera_description.0.clone()), RenderLayers::layer(0)));
+            });
+        }
 
         // Force RGB cameras to render layer 0
         commands.entity(entity).insert(RenderLayers::layer(0));
@@ -91,6 +300,38 @@ fn spawn_segmentation_cameras(
     }
 }
 
+/// Keeps segmentation, depth and capture cameras in line with their RGB camera when it changes
+#[allow(clippy::type_complexity)]
+fn sync_twin_cameras(
+    camera_query: Query<
+        (&Camera, Option<&Projection>, &Children),
+        (With<RGBCamera>, Or<(Changed<Camera>, Changed<Projection>)>),
+    >,
+    mut twin_query: Query<
+        (&mut Camera, &mut Projection, Has<DepthCamera>, Option<&CaptureCamera>),
+        (
+            Or<(With<SegmentationCamera>, With<DepthCamera>, With<CaptureCamera>)>,
+            Without<RGBCamera>,
+        ),
+    >,
+) {
+    for (camera, projection, children) in camera_query.iter() {
+        for child in children.iter() {
+            let Ok((mut twin, mut twin_projection, is_depth, capture)) = twin_query.get_mut(*child) else {
+                continue;
+            };
+
+            let fixed_hdr = is_depth || capture.is_some_and(|capture| capture.format.is_hdr());
+            mirror_camera(camera, &mut twin, !fixed_hdr);
+
+            if let Some(projection) = projection {
+                *twin_projection = projection.clone();
+            }
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
 fn spawn_segmentation_materials(
     mut commands: Commands,
     mut materials: ResMut<Assets<StandardMaterial>>,
@@ -104,6 +345,10 @@ fn spawn_segmentation_materials(
         ),
     >,
 ) {
+    if query.is_empty() {
+        return;
+    }
+
     for (entity, mesh_handle, segmentation_object) in query.iter() {
         commands.entity(entity).with_children(|parent| {
             parent.spawn((
@@ -117,6 +362,7 @@ fn spawn_segmentation_materials(
                     ..default()
                 },
                 RenderLayers::layer(1),
+                SegmentationTwin,
             ));
         });
 
@@ -128,22 +374,41 @@ fn spawn_segmentation_materials(
     info!("Spawned Segmentation Materials");
 }
 
-fn toggle_segmentation_view(
-    keys: Res<ButtonInput<KeyCode>>,
-    mut camera_query: Query<&mut Camera, With<SegmentationCamera>>,
+/// Gives every mesh a depth twin on layer 2, only needed when a `DepthCamera` exists
+#[allow(clippy::type_complexity)]
+fn spawn_depth_materials(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<DepthMaterial>>,
+    depth_cameras: Query<(), With<DepthCamera>>,
+    query: Query<
+        (Entity, &Handle<Mesh>),
+        (
+            Without<SegmentationTwin>,
+            Without<DepthTwin>,
+            Without<DepthTwinParent>,
+        ),
+    >,
 ) {
-    for mut camera in camera_query.iter_mut() {
-        match camera.target {
-            RenderTarget::Window(_) => {
-                if keys.just_pressed(KeyCode::Space) {
-                    camera.is_active = true;
-                }
-            
-                if keys.just_released(KeyCode::Space)  {
-                    camera.is_active = false;
-                }
-            },
-            _ => {},
-        };
+    if depth_cameras.is_empty() || query.is_empty() {
+        return;
+    }
+
+    let material = materials.add(DepthMaterial {});
+
+    for (entity, mesh_handle) in query.iter() {
+        commands.entity(entity).with_children(|parent| {
+            parent.spawn((
+                MaterialMeshBundle {
+                    mesh: mesh_handle.clone(),
+                    material: material.clone(),
+                    ..default()
+                },
+                RenderLayers::layer(2),
+                DepthTwin,
+            ));
+        });
+
+        commands.entity(entity).insert(DepthTwinParent);
     }
+    info!("Spawned Depth Materials");
 }
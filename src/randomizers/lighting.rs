@@ -0,0 +1,340 @@
+//! Lighting Randomizers
+//!
+//! Vary the illumination of a scene: the intensity, color temperature, pose and shadows of
+//! `DirectionalLight`s, `PointLight`s and `SpotLight`s and how many of them are on, the
+//! `AmbientLight` and the intensity of `EnvironmentMapLight`s. Intensities are factors of the
+//! value the scene was set up with, so one job works for scenes lit in lux or in lumens.
+//!
+//! Lights can only be switched off, a job that should render with up to four lights lists four.
+
+use bevy::prelude::*;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::marker::PhantomData;
+
+use crate::{
+    randomizers::{component_base, keyed, randomizer_base, symmetric, Randomizer},
+    resources::SegmentationRng,
+};
+
+/// Light components the `LightRandomizer` varies
+pub trait RandomLight: Component + Clone {
+    /// Default name of the randomizer and key of lights without a `Name`
+    const NAME: &'static str;
+    /// Whether moving the light changes the lighting
+    const POSITIONED: bool;
+    /// Whether turning the light changes the lighting
+    const DIRECTED: bool;
+
+    /// Lux for directional lights, lumens otherwise
+    fn intensity_mut(&mut self) -> &mut f32;
+    fn color_mut(&mut self) -> &mut Color;
+    fn shadows_mut(&mut self) -> &mut bool;
+}
+
+impl RandomLight for DirectionalLight {
+    const NAME: &'static str = "directional_light";
+    const POSITIONED: bool = false;
+    const DIRECTED: bool = true;
+
+    fn intensity_mut(&mut self) -> &mut f32 {
+        &mut self.illuminance
+    }
+
+    fn color_mut(&mut self) -> &mut Color {
+        &mut self.color
+    }
+
+    fn shadows_mut(&mut self) -> &mut bool {
+        &mut self.shadows_enabled
+    }
+}
+
+impl RandomLight for PointLight {
+    const NAME: &'static str = "point_light";
+    const POSITIONED: bool = true;
+    const DIRECTED: bool = false;
+
+    fn intensity_mut(&mut self) -> &mut f32 {
+        &mut self.intensity
+    }
+
+    fn color_mut(&mut self) -> &mut Color {
+        &mut self.color
+    }
+
+    fn shadows_mut(&mut self) -> &mut bool {
+        &mut self.shadows_enabled
+    }
+}
+
+impl RandomLight for SpotLight {
+    const NAME: &'static str = "spot_light";
+    const POSITIONED: bool = true;
+    const DIRECTED: bool = true;
+
+    fn intensity_mut(&mut self) -> &mut f32 {
+        &mut self.intensity
+    }
+
+    fn color_mut(&mut self) -> &mut Color {
+        &mut self.color
+    }
+
+    fn shadows_mut(&mut self) -> &mut bool {
+        &mut self.shadows_enabled
+    }
+}
+
+/// Varies every light of type `L` around its base
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, bound(deserialize = "L: RandomLight", serialize = ""))]
+pub struct LightRandomizer<L> {
+    pub name: String,
+    /// Smallest and largest factor of the base intensity
+    pub intensity: [f32; 2],
+    /// Smallest and largest color temperature in Kelvin, `None` keeps the base color
+    pub temperature: Option<[f32; 2]>,
+    /// Largest offset along each axis in meters, point and spot lights only
+    pub translation: [f32; 3],
+    /// Largest rotation around each axis in radians, applied in XYZ order, directional and spot
+    /// lights only
+    pub rotation: [f32; 3],
+    /// Chance a light casts shadows, `None` keeps the base setting
+    pub shadows: Option<f32>,
+    /// Fewest and most lights that are on, `None` keeps all on
+    pub count: Option<[u32; 2]>,
+    #[serde(skip)]
+    light: PhantomData<L>,
+}
+
+pub type DirectionalLightRandomizer = LightRandomizer<DirectionalLight>;
+pub type PointLightRandomizer = LightRandomizer<PointLight>;
+pub type SpotLightRandomizer = LightRandomizer<SpotLight>;
+
+impl<L: RandomLight> Default for LightRandomizer<L> {
+    fn default() -> Self {
+        LightRandomizer {
+            name: String::from(L::NAME),
+            intensity: [0.5, 1.5],
+            temperature: Some([3000.0, 8000.0]),
+            translation: [1.0, 1.0, 1.0],
+            rotation: [0.3, 0.3, 0.0],
+            shadows: None,
+            count: None,
+            light: PhantomData,
+        }
+    }
+}
+
+impl<L: RandomLight> LightRandomizer<L> {
+    /// Which of `lights` lights are on in this step
+    fn switched_on(&self, lights: usize, rng: &SegmentationRng) -> Vec<bool> {
+        let Some([min, max]) = self.count else {
+            return vec![true; lights];
+        };
+
+        let mut rng = rng.frame_stream(&format!("{}/count", self.name));
+        let count = (rng.gen_range(min.min(max)..=min.max(max)) as usize).min(lights);
+        let mut order: Vec<usize> = (0..lights).collect();
+        order.shuffle(&mut rng);
+
+        let mut on = vec![false; lights];
+        for &i in order.iter().take(count) {
+            on[i] = true;
+        }
+        on
+    }
+}
+
+impl<L: RandomLight> Randomizer for LightRandomizer<L> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn randomize(&mut self, world: &mut World, rng: &SegmentationRng) -> Value {
+        let mut parameters = Map::new();
+
+        let mut query = world.query_filtered::<Entity, With<L>>();
+        let lights: Vec<(Entity, String)> = query
+            .iter(world)
+            .map(|entity| (entity, L::NAME.to_string()))
+            .collect();
+        let lights = keyed(world, lights);
+        let on = self.switched_on(lights.len(), rng);
+
+        for ((entity, key), on) in lights.into_iter().zip(on) {
+            let (Some(base), Some(mut light)) = (randomizer_base(world, entity), component_base::<L>(world, entity))
+            else {
+                continue;
+            };
+
+            let mut rng = rng.frame_stream(&format!("{}/{}", self.name, key));
+            let [min, max] = self.intensity;
+            let intensity = *light.intensity_mut() * rng.gen_range(min.min(max)..=min.max(max));
+            let temperature = self
+                .temperature
+                .map(|[min, max]| rng.gen_range(min.min(max)..=min.max(max)));
+            let shadows = match self.shadows {
+                Some(chance) => rng.gen::<f32>() < chance,
+                None => *light.shadows_mut(),
+            };
+
+            *light.intensity_mut() = intensity;
+            if let Some(temperature) = temperature {
+                *light.color_mut() = color_temperature(temperature);
+            }
+            *light.shadows_mut() = shadows;
+
+            let mut transform = base.transform;
+            let mut light_parameters = json!({
+                "on": on,
+                "intensity": intensity,
+                "temperature": temperature,
+                "shadows": shadows,
+            });
+            if L::POSITIONED {
+                let offset = self.translation.map(|extent| symmetric(&mut rng, extent));
+                transform.translation += Vec3::from(offset);
+                light_parameters["translation"] = json!(offset);
+            }
+            if L::DIRECTED {
+                let angles = self.rotation.map(|extent| symmetric(&mut rng, extent));
+                transform.rotation *= Quat::from_euler(EulerRot::XYZ, angles[0], angles[1], angles[2]);
+                light_parameters["rotation"] = json!(angles);
+            }
+
+            let visibility = if on { base.visibility } else { Visibility::Hidden };
+            world.entity_mut(entity).insert((light, transform, visibility));
+            parameters.insert(key, light_parameters);
+        }
+
+        Value::Object(parameters)
+    }
+}
+
+/// Varies the `AmbientLight` around its base
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AmbientLightRandomizer {
+    pub name: String,
+    /// Smallest and largest factor of the base brightness
+    pub brightness: [f32; 2],
+    /// Smallest and largest color temperature in Kelvin, `None` keeps the base color
+    pub temperature: Option<[f32; 2]>,
+    #[serde(skip)]
+    base: Option<AmbientLight>,
+}
+
+impl Default for AmbientLightRandomizer {
+    fn default() -> Self {
+        AmbientLightRandomizer {
+            name: String::from("ambient_light"),
+            brightness: [0.5, 1.5],
+            temperature: None,
+            base: None,
+        }
+    }
+}
+
+impl Randomizer for AmbientLightRandomizer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn randomize(&mut self, world: &mut World, rng: &SegmentationRng) -> Value {
+        let Some(mut ambient) = world.get_resource_mut::<AmbientLight>() else {
+            return Value::Null;
+        };
+        let base = self.base.get_or_insert_with(|| ambient.clone());
+
+        let mut rng = rng.frame_stream(&self.name);
+        let [min, max] = self.brightness;
+        let brightness = base.brightness * rng.gen_range(min.min(max)..=min.max(max));
+        let temperature = self
+            .temperature
+            .map(|[min, max]| rng.gen_range(min.min(max)..=min.max(max)));
+
+        ambient.brightness = brightness;
+        ambient.color = temperature.map_or(base.color, color_temperature);
+        json!({ "brightness": brightness, "temperature": temperature })
+    }
+}
+
+/// Varies the intensity of every `EnvironmentMapLight` around its base
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EnvironmentMapRandomizer {
+    pub name: String,
+    /// Smallest and largest factor of the base intensity
+    pub intensity: [f32; 2],
+}
+
+impl Default for EnvironmentMapRandomizer {
+    fn default() -> Self {
+        EnvironmentMapRandomizer {
+            name: String::from("environment_map"),
+            intensity: [0.5, 1.5],
+        }
+    }
+}
+
+impl Randomizer for EnvironmentMapRandomizer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn randomize(&mut self, world: &mut World, rng: &SegmentationRng) -> Value {
+        let mut parameters = Map::new();
+
+        let mut query = world.query_filtered::<Entity, With<EnvironmentMapLight>>();
+        let maps: Vec<(Entity, String)> = query
+            .iter(world)
+            .map(|entity| (entity, self.name.clone()))
+            .collect();
+
+        for (entity, key) in keyed(world, maps) {
+            let Some(base) = component_base::<EnvironmentMapLight>(world, entity) else {
+                continue;
+            };
+
+            let mut rng = rng.frame_stream(&format!("{}/{}", self.name, key));
+            let [min, max] = self.intensity;
+            let intensity = base.intensity * rng.gen_range(min.min(max)..=min.max(max));
+
+            if let Some(mut map) = world.get_mut::<EnvironmentMapLight>(entity) {
+                map.intensity = intensity;
+            }
+            parameters.insert(key, intensity.into());
+        }
+
+        Value::Object(parameters)
+    }
+}
+
+/// sRGB color of a black body at `kelvin`, after Tanner Helland's fit, good from 1000K to 40000K
+pub fn color_temperature(kelvin: f32) -> Color {
+    let t = kelvin.clamp(1000.0, 40000.0) / 100.0;
+
+    let red = if t <= 66.0 {
+        255.0
+    } else {
+        329.69873 * (t - 60.0).powf(-0.13320476)
+    };
+    let green = if t <= 66.0 {
+        99.4708 * t.ln() - 161.11957
+    } else {
+        288.12216 * (t - 60.0).powf(-0.07551485)
+    };
+    let blue = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.51773 * (t - 10.0).ln() - 305.0448
+    };
+
+    let channel = |value: f32| value.clamp(0.0, 255.0) / 255.0;
+    Color::srgb(channel(red), channel(green), channel(blue))
+}
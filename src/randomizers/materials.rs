@@ -0,0 +1,179 @@
+//! Material Randomizer
+//!
+//! Varies the `StandardMaterial` of `SegmentationObject`s and, if asked to, of the background,
+//! every other lit mesh such as floors and walls. Every mesh gets its own copy of its material
+//! first, see `own_material`. Segmentation twins have their own unlit materials and are never
+//! touched, so the masks stay the same whatever the objects look like.
+//!
+//! Base color textures come from a folder of images in the assets. It is loaded on the first
+//! randomization, capture waits until it is, and the images are sorted by path, so a seed
+//! picks the same texture in every run.
+
+use bevy::{asset::LoadedFolder, prelude::*};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    components::{SegmentationObject, SegmentationTwin},
+    randomizers::{component_base, keyed, labeled_objects, own_material, Randomizer},
+    resources::{CaptureReadiness, SegmentationRng},
+    utils::random_color,
+};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MaterialRandomizer {
+    pub name: String,
+    pub labels: Vec<String>,
+    /// Also randomizes meshes that are no `SegmentationObject`
+    pub backgrounds: bool,
+    /// Draws a base color, otherwise it is left alone
+    pub base_color: bool,
+    /// Smallest and largest perceptual roughness, `None` leaves it alone
+    pub roughness: Option<[f32; 2]>,
+    /// Smallest and largest metallic, `None` leaves it alone
+    pub metallic: Option<[f32; 2]>,
+    /// Folder in the assets with base color textures, e.g. `textures/wood`, only images
+    pub textures: Option<String>,
+    /// Chance a mesh gets one of the `textures`, it keeps its base texture otherwise
+    pub texture_chance: f32,
+    #[serde(skip)]
+    folder: Option<Handle<LoadedFolder>>,
+    /// Images of the folder sorted by path, once it is loaded
+    #[serde(skip)]
+    images: Option<Vec<(String, Handle<Image>)>>,
+}
+
+impl Default for MaterialRandomizer {
+    fn default() -> Self {
+        MaterialRandomizer {
+            name: String::from("material"),
+            labels: vec![],
+            backgrounds: false,
+            base_color: true,
+            roughness: Some([0.1, 1.0]),
+            metallic: Some([0.0, 1.0]),
+            textures: None,
+            texture_chance: 0.5,
+            folder: None,
+            images: None,
+        }
+    }
+}
+
+impl MaterialRandomizer {
+    /// Images to draw textures from, empty until the folder is loaded
+    fn images(&mut self, world: &mut World) -> &[(String, Handle<Image>)] {
+        let Some(path) = &self.textures else {
+            return &[];
+        };
+
+        if self.folder.is_none() {
+            let folder = world.resource::<AssetServer>().load_folder(path.clone());
+            if let Some(mut readiness) = world.get_resource_mut::<CaptureReadiness>() {
+                readiness.track(&folder);
+            }
+            self.folder = Some(folder);
+        }
+
+        if self.images.is_none() {
+            let asset_server = world.resource::<AssetServer>();
+            let folders = world.resource::<Assets<LoadedFolder>>();
+            if let Some(folder) = self.folder.as_ref().and_then(|folder| folders.get(folder)) {
+                let mut images: Vec<(String, Handle<Image>)> = folder
+                    .handles
+                    .iter()
+                    .filter_map(|handle| {
+                        let image = handle.clone().try_typed::<Image>().ok()?;
+                        Some((asset_server.get_path(image.id())?.to_string(), image))
+                    })
+                    .collect();
+                images.sort_by(|(a, _), (b, _)| a.cmp(b));
+                if images.is_empty() {
+                    warn!("No images in texture folder {}", path);
+                }
+                self.images = Some(images);
+            }
+        }
+
+        self.images.as_deref().unwrap_or_default()
+    }
+}
+
+impl Randomizer for MaterialRandomizer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn randomize(&mut self, world: &mut World, rng: &SegmentationRng) -> Value {
+        let mut parameters = Map::new();
+        let images = self.images(world).to_vec();
+
+        let mut meshes = labeled_objects(world, &self.labels);
+        if self.backgrounds {
+            let mut query = world.query_filtered::<Entity, (
+                With<Handle<StandardMaterial>>,
+                Without<SegmentationObject>,
+                Without<SegmentationTwin>,
+            )>();
+            let backgrounds: Vec<(Entity, String)> = query
+                .iter(world)
+                .map(|entity| (entity, String::from("background")))
+                .collect();
+            meshes.extend(keyed(world, backgrounds));
+        }
+
+        for (entity, key) in meshes {
+            let Some(handle) = own_material(world, entity) else {
+                continue;
+            };
+            let base_texture = component_base::<Handle<StandardMaterial>>(world, entity)
+                .and_then(|base| world.resource::<Assets<StandardMaterial>>().get(&base)?.base_color_texture.clone());
+
+            let mut rng = rng.frame_stream(&format!("{}/{}", self.name, key));
+            let base_color = self.base_color.then(|| random_color(&mut rng));
+            let roughness = self
+                .roughness
+                .map(|[min, max]| rng.gen_range(min.min(max)..=min.max(max)));
+            let metallic = self
+                .metallic
+                .map(|[min, max]| rng.gen_range(min.min(max)..=min.max(max)));
+            let texture = (!images.is_empty() && rng.gen::<f32>() < self.texture_chance)
+                .then(|| &images[rng.gen_range(0..images.len())]);
+
+            let mut materials = world.resource_mut::<Assets<StandardMaterial>>();
+            let Some(material) = materials.get_mut(&handle) else {
+                continue;
+            };
+            if let Some(color) = base_color {
+                material.base_color = color;
+            }
+            if let Some(roughness) = roughness {
+                material.perceptual_roughness = roughness;
+            }
+            if let Some(metallic) = metallic {
+                material.metallic = metallic;
+            }
+            if self.textures.is_some() {
+                material.base_color_texture = texture.map_or(base_texture, |(_, image)| Some(image.clone()));
+            }
+
+            let base_color = base_color.map(|color| {
+                let color = color.to_srgba();
+                [color.red, color.green, color.blue]
+            });
+            parameters.insert(
+                key,
+                json!({
+                    "base_color": base_color,
+                    "roughness": roughness,
+                    "metallic": metallic,
+                    "texture": texture.map(|(path, _)| path),
+                }),
+            );
+        }
+
+        Value::Object(parameters)
+    }
+}
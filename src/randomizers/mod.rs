@@ -0,0 +1,261 @@
+//! Randomizers
+//!
+//! Domain randomization: every registered `Randomizer` changes the scene before a step is
+//! rendered, e.g. moves, hides or retextures the `SegmentationObject`s or dims the lights, and
+//! returns the parameters it applied, which end up in the `FrameMetadata` under its name.
+//!
+//! Randomizers draw from the `SegmentationRng` of the step, so a frame looks the same in every
+//! run with the same seed, whether it is rendered in order, resumed or in a shard. They run in
+//! `Update` on every `Randomizers::interval`-th step, a `JobPlugin` matches the interval to its
+//! capture policy so they run once before each capture. While the scene loads they keep applying
+//! the first step to objects as they spawn.
+//!
+//! Randomizers change objects relative to their state before the first randomization, kept in
+//! `RandomizerBase` and `ComponentBase`, so parameters never accumulate over steps.
+
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    components::SegmentationObject,
+    resources::{label_rules, FrameMetadata, SegmentationRng},
+};
+
+pub mod lighting;
+pub mod materials;
+pub mod objects;
+
+pub use lighting::{
+    AmbientLightRandomizer, DirectionalLightRandomizer, EnvironmentMapRandomizer, LightRandomizer,
+    PointLightRandomizer, RandomLight, SpotLightRandomizer,
+};
+pub use materials::MaterialRandomizer;
+pub use objects::{ColorRandomizer, PoseRandomizer, ScaleRandomizer, VisibilityRandomizer};
+
+pub trait Randomizer: Send + Sync + 'static {
+    /// Key of the parameters in the `FrameMetadata`, unique among the registered randomizers
+    fn name(&self) -> &str;
+
+    /// Applies the parameters of the current step of `rng` to `world` and returns them
+    fn randomize(&mut self, world: &mut World, rng: &SegmentationRng) -> Value;
+}
+
+/// Registered randomizers, run in the order they were added
+#[derive(Resource)]
+pub struct Randomizers {
+    randomizers: Vec<Box<dyn Randomizer>>,
+    /// Steps between two randomizations
+    pub interval: u32,
+}
+
+impl Default for Randomizers {
+    fn default() -> Self {
+        Randomizers {
+            randomizers: Vec::new(),
+            interval: 1,
+        }
+    }
+}
+
+impl Randomizers {
+    pub fn add(&mut self, randomizer: impl Randomizer) -> &mut Self {
+        self.randomizers.push(Box::new(randomizer));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.randomizers.is_empty()
+    }
+}
+
+/// Adds `add_randomizer` to the `App`
+pub trait RandomizerAppExt {
+    fn add_randomizer(&mut self, randomizer: impl Randomizer) -> &mut Self;
+}
+
+impl RandomizerAppExt for App {
+    fn add_randomizer(&mut self, randomizer: impl Randomizer) -> &mut Self {
+        self.world_mut()
+            .get_resource_or_insert_with(Randomizers::default)
+            .add(randomizer);
+        self
+    }
+}
+
+/// Randomizers shipped with the crate, as they are listed in a `JobSpec`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum RandomizerSpec {
+    Pose(PoseRandomizer),
+    Scale(ScaleRandomizer),
+    Visibility(VisibilityRandomizer),
+    Color(ColorRandomizer),
+    DirectionalLight(DirectionalLightRandomizer),
+    PointLight(PointLightRandomizer),
+    SpotLight(SpotLightRandomizer),
+    AmbientLight(AmbientLightRandomizer),
+    EnvironmentMap(EnvironmentMapRandomizer),
+    Material(MaterialRandomizer),
+}
+
+impl RandomizerSpec {
+    pub fn add_to(&self, randomizers: &mut Randomizers) {
+        match self.clone() {
+            RandomizerSpec::Pose(randomizer) => randomizers.add(randomizer),
+            RandomizerSpec::Scale(randomizer) => randomizers.add(randomizer),
+            RandomizerSpec::Visibility(randomizer) => randomizers.add(randomizer),
+            RandomizerSpec::Color(randomizer) => randomizers.add(randomizer),
+            RandomizerSpec::DirectionalLight(randomizer) => randomizers.add(randomizer),
+            RandomizerSpec::PointLight(randomizer) => randomizers.add(randomizer),
+            RandomizerSpec::SpotLight(randomizer) => randomizers.add(randomizer),
+            RandomizerSpec::AmbientLight(randomizer) => randomizers.add(randomizer),
+            RandomizerSpec::EnvironmentMap(randomizer) => randomizers.add(randomizer),
+            RandomizerSpec::Material(randomizer) => randomizers.add(randomizer),
+        };
+    }
+}
+
+pub struct RandomizerPlugin;
+
+impl Plugin for RandomizerPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<Randomizers>()
+            // objects labeled this update are randomized before their first frame
+            .add_systems(Update, run_randomizers.after(label_rules::apply_label_rules));
+    }
+}
+
+/// State of an object before it was first randomized
+#[derive(Component, Clone, Debug)]
+pub struct RandomizerBase {
+    pub transform: Transform,
+    pub visibility: Visibility,
+}
+
+/// Base of `entity`, recorded the first time it is asked for
+pub fn randomizer_base(world: &mut World, entity: Entity) -> Option<RandomizerBase> {
+    let mut entity = world.get_entity_mut(entity)?;
+    if let Some(base) = entity.get::<RandomizerBase>() {
+        return Some(base.clone());
+    }
+
+    let base = RandomizerBase {
+        transform: entity.get::<Transform>().copied().unwrap_or_default(),
+        visibility: entity.get::<Visibility>().copied().unwrap_or_default(),
+    };
+    entity.insert(base.clone());
+    Some(base)
+}
+
+/// Base of a component, e.g. a light, that randomizers other than pose and visibility change
+#[derive(Component, Clone, Debug)]
+pub struct ComponentBase<T>(pub T);
+
+/// `T` of `entity` before it was first randomized, recorded the first time it is asked for
+pub fn component_base<T: Component + Clone>(world: &mut World, entity: Entity) -> Option<T> {
+    let mut entity = world.get_entity_mut(entity)?;
+    if let Some(base) = entity.get::<ComponentBase<T>>() {
+        return Some(base.0.clone());
+    }
+
+    let base = entity.get::<T>()?.clone();
+    entity.insert(ComponentBase(base.clone()));
+    Some(base)
+}
+
+/// `SegmentationObject`s with one of `labels`, all with no labels, with a key that is stable
+/// between runs, see `object_key`. Sorted by key.
+pub fn labeled_objects(world: &mut World, labels: &[String]) -> Vec<(Entity, String)> {
+    let mut query = world.query::<(Entity, &SegmentationObject)>();
+    let objects: Vec<(Entity, String)> = query
+        .iter(world)
+        .filter(|(_, object)| labels.is_empty() || labels.contains(&object.0))
+        .map(|(entity, object)| (entity, object.0.clone()))
+        .collect();
+
+    keyed(world, objects)
+}
+
+/// The `Name`s of `entity` and its ancestors joined by `/`, e.g. `CarBody/Mesh`, since glTF
+/// meshes are often only named after their node. `fallback` without any names.
+pub fn object_key(world: &World, entity: Entity, fallback: &str) -> String {
+    let mut names = Vec::new();
+    let mut current = Some(entity);
+    while let Some(entity) = current {
+        if let Some(name) = world.get::<Name>(entity) {
+            names.push(name.as_str());
+        }
+        current = world.get::<Parent>(entity).map(|parent| parent.get());
+    }
+
+    if names.is_empty() {
+        return fallback.to_string();
+    }
+    names.reverse();
+    names.join("/")
+}
+
+/// Keys `entities` with `object_key`, numbered when several share one, sorted by key
+pub(crate) fn keyed(world: &World, entities: Vec<(Entity, String)>) -> Vec<(Entity, String)> {
+    let mut objects: Vec<(Entity, String)> = entities
+        .into_iter()
+        .map(|(entity, fallback)| (entity, object_key(world, entity, &fallback)))
+        .collect();
+
+    // scenes spawn in the same order in every run, so entities break ties between equal names
+    objects.sort_by(|(a, a_key), (b, b_key)| a_key.cmp(b_key).then(a.cmp(b)));
+
+    let mut seen: HashMap<String, u32> = HashMap::new();
+    for (_, key) in objects.iter_mut() {
+        let count = seen.entry(key.clone()).or_default();
+        if *count > 0 {
+            *key = format!("{}#{}", key, count);
+        }
+        *count += 1;
+    }
+    objects
+}
+
+/// Marks a material that only this entity uses
+#[derive(Component)]
+pub struct OwnMaterial;
+
+/// Material of `entity`, copied the first time so changing it leaves other entities alone. The
+/// handle of the shared material is kept as its `ComponentBase`.
+pub fn own_material(world: &mut World, entity: Entity) -> Option<Handle<StandardMaterial>> {
+    let entity_ref = world.get_entity(entity)?;
+    let handle = entity_ref.get::<Handle<StandardMaterial>>()?.clone();
+    if entity_ref.contains::<OwnMaterial>() {
+        return Some(handle);
+    }
+    component_base::<Handle<StandardMaterial>>(world, entity)?;
+
+    let mut materials = world.resource_mut::<Assets<StandardMaterial>>();
+    let material = materials.get(&handle)?.clone();
+    let handle = materials.add(material);
+    world.entity_mut(entity).insert((handle.clone(), OwnMaterial));
+    Some(handle)
+}
+
+/// Uniform in `-extent..=extent`
+pub(crate) fn symmetric(rng: &mut impl rand::Rng, extent: f32) -> f32 {
+    let extent = extent.abs();
+    rng.gen_range(-extent..=extent)
+}
+
+fn run_randomizers(world: &mut World) {
+    world.resource_scope(|world, mut randomizers: Mut<Randomizers>| {
+        let rng = world.resource::<SegmentationRng>().clone();
+        if randomizers.is_empty() || rng.step() % randomizers.interval.max(1) != 0 {
+            return;
+        }
+
+        for randomizer in randomizers.randomizers.iter_mut() {
+            let parameters = randomizer.randomize(world, &rng);
+            world
+                .resource_mut::<FrameMetadata>()
+                .insert(rng.frame(), randomizer.name(), parameters);
+        }
+    });
+}
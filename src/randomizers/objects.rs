@@ -0,0 +1,217 @@
+//! Object Randomizers
+//!
+//! Pose, scale, visibility and color of `SegmentationObject`s. Each draws from its own stream per
+//! object, keyed by the randomizer and object names, so objects spawning later or other
+//! randomizers do not change the values an object gets. All of them only touch objects with one
+//! of their `labels`, every object without labels.
+//!
+//! Segmentation twins are children of their object and follow its transform and visibility, the
+//! color only changes the lit material.
+
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    randomizers::{labeled_objects, own_material, randomizer_base, symmetric, Randomizer},
+    resources::SegmentationRng,
+    utils::random_color,
+};
+
+/// Moves and turns objects away from their base pose
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PoseRandomizer {
+    pub name: String,
+    pub labels: Vec<String>,
+    /// Largest offset along each axis in meters
+    pub translation: [f32; 3],
+    /// Largest rotation around each axis in radians, applied in XYZ order
+    pub rotation: [f32; 3],
+}
+
+impl Default for PoseRandomizer {
+    fn default() -> Self {
+        PoseRandomizer {
+            name: String::from("pose"),
+            labels: vec![],
+            translation: [0.5, 0.0, 0.5],
+            rotation: [0.0, std::f32::consts::PI, 0.0],
+        }
+    }
+}
+
+impl Randomizer for PoseRandomizer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn randomize(&mut self, world: &mut World, rng: &SegmentationRng) -> Value {
+        let mut parameters = Map::new();
+
+        for (entity, key) in labeled_objects(world, &self.labels) {
+            let Some(base) = randomizer_base(world, entity) else {
+                continue;
+            };
+
+            let mut rng = rng.frame_stream(&format!("{}/{}", self.name, key));
+            let offset = self.translation.map(|extent| symmetric(&mut rng, extent));
+            let angles = self.rotation.map(|extent| symmetric(&mut rng, extent));
+
+            if let Some(mut transform) = world.get_mut::<Transform>(entity) {
+                transform.translation = base.transform.translation + Vec3::from(offset);
+                transform.rotation = base.transform.rotation
+                    * Quat::from_euler(EulerRot::XYZ, angles[0], angles[1], angles[2]);
+            }
+            parameters.insert(key, json!({ "translation": offset, "rotation": angles }));
+        }
+
+        Value::Object(parameters)
+    }
+}
+
+/// Scales objects by a factor of their base scale
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ScaleRandomizer {
+    pub name: String,
+    pub labels: Vec<String>,
+    pub min: f32,
+    pub max: f32,
+    /// Draws a factor per axis instead of one for all
+    pub per_axis: bool,
+}
+
+impl Default for ScaleRandomizer {
+    fn default() -> Self {
+        ScaleRandomizer {
+            name: String::from("scale"),
+            labels: vec![],
+            min: 0.8,
+            max: 1.2,
+            per_axis: false,
+        }
+    }
+}
+
+impl Randomizer for ScaleRandomizer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn randomize(&mut self, world: &mut World, rng: &SegmentationRng) -> Value {
+        let mut parameters = Map::new();
+        let (min, max) = (self.min.min(self.max), self.min.max(self.max));
+
+        for (entity, key) in labeled_objects(world, &self.labels) {
+            let Some(base) = randomizer_base(world, entity) else {
+                continue;
+            };
+
+            let mut rng = rng.frame_stream(&format!("{}/{}", self.name, key));
+            let factor = if self.per_axis {
+                Vec3::from([0; 3].map(|_| rng.gen_range(min..=max)))
+            } else {
+                Vec3::splat(rng.gen_range(min..=max))
+            };
+
+            if let Some(mut transform) = world.get_mut::<Transform>(entity) {
+                transform.scale = base.transform.scale * factor;
+            }
+            parameters.insert(key, json!(factor.to_array()));
+        }
+
+        Value::Object(parameters)
+    }
+}
+
+/// Hides objects, e.g. to vary how many of a class are in view
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct VisibilityRandomizer {
+    pub name: String,
+    pub labels: Vec<String>,
+    /// Chance an object keeps its base visibility
+    pub visible: f32,
+}
+
+impl Default for VisibilityRandomizer {
+    fn default() -> Self {
+        VisibilityRandomizer {
+            name: String::from("visibility"),
+            labels: vec![],
+            visible: 0.8,
+        }
+    }
+}
+
+impl Randomizer for VisibilityRandomizer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn randomize(&mut self, world: &mut World, rng: &SegmentationRng) -> Value {
+        let mut parameters = Map::new();
+
+        for (entity, key) in labeled_objects(world, &self.labels) {
+            let Some(base) = randomizer_base(world, entity) else {
+                continue;
+            };
+
+            let mut rng = rng.frame_stream(&format!("{}/{}", self.name, key));
+            let visible = rng.gen::<f32>() < self.visible;
+
+            if let Some(mut visibility) = world.get_mut::<Visibility>(entity) {
+                *visibility = if visible { base.visibility } else { Visibility::Hidden };
+            }
+            parameters.insert(key, visible.into());
+        }
+
+        Value::Object(parameters)
+    }
+}
+
+/// Gives objects a random base color, objects sharing a material get their own copy first
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ColorRandomizer {
+    pub name: String,
+    pub labels: Vec<String>,
+}
+
+impl Default for ColorRandomizer {
+    fn default() -> Self {
+        ColorRandomizer {
+            name: String::from("color"),
+            labels: vec![],
+        }
+    }
+}
+
+impl Randomizer for ColorRandomizer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn randomize(&mut self, world: &mut World, rng: &SegmentationRng) -> Value {
+        let mut parameters = Map::new();
+
+        for (entity, key) in labeled_objects(world, &self.labels) {
+            let Some(handle) = own_material(world, entity) else {
+                continue;
+            };
+
+            let mut rng = rng.frame_stream(&format!("{}/{}", self.name, key));
+            let color = random_color(&mut rng);
+
+            if let Some(material) = world.resource_mut::<Assets<StandardMaterial>>().get_mut(&handle) {
+                material.base_color = color;
+            }
+            let color = color.to_srgba();
+            parameters.insert(key, json!([color.red, color.green, color.blue]));
+        }
+
+        Value::Object(parameters)
+    }
+}
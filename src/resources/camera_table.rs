@@ -6,55 +6,161 @@ use bevy::{
         camera::RenderTarget,
         render_asset::RenderAssetUsages,
         render_resource::{
-            Extent3d, TextureDimension, TextureFormat,
+            Extent3d, TextureFormat,
             TextureUsages,
         },
         renderer::RenderDevice,
-        texture::BevyDefault,
+        texture::{BevyDefault, TextureFormatPixelInfo},
     }
 };
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::Path};
 use crossbeam_channel::{Receiver, Sender};
-use crate::utils::image_copy::ImageCopier;
+use crate::{
+    components::Modality,
+    error::SegmentationError,
+    resources::{split::Split, OutputConfig},
+    utils::{
+        encoding::{save_image, Encoding},
+        hdr::tonemapped_preview,
+        image_copy::{ImageCopier, ImageReadback, ReadbackLayout},
+        image_writer::ImageWriter,
+    },
+};
+
+/// Incomplete frames this many frames behind the newest readback will not complete anymore
+pub(crate) const MAX_PENDING_FRAMES: u32 = 16;
+
+/// Sent once every camera in the `CameraOutputTable` delivered `frame`, the table's images then
+/// hold that frame
+#[derive(Event, Clone, Copy, Debug)]
+pub struct FrameBundle {
+    pub frame: u32,
+}
 
 // CPU world resource to access images
-#[derive(Resource)]
+#[derive(Resource, Default)]
 pub struct CameraOutputTable{
+    /// Output names, see `Modality::output_name`
     pub camera_names: Vec<String>,
+    /// Camera each output belongs to
+    pub sources: Vec<String>,
+    pub modalities: Vec<Modality>,
     pub image_handles: Vec<Handle<Image>>,
-    pub preroll: u32,
-    pub receivers: Vec<Receiver<Vec<u8>>>,
-}
-
-impl Default for CameraOutputTable {
-    fn default() -> Self {
-        CameraOutputTable {
-            camera_names: vec![],
-            image_handles: vec![],
-            preroll: 40,
-            receivers: vec![],
-        }
-    }
+    pub receivers: Vec<Receiver<ImageReadback>>,
+    /// Frame the images currently hold, all cameras show the same frame
+    pub frame: Option<u32>,
+    /// Sequence number of the bundle the images hold within this run
+    pub capture_index: Option<u32>,
+    /// Split the bundle the images hold belongs to, see `SplitPolicy`
+    pub split: Option<Split>,
+    /// Milliseconds since the unix epoch when the bundle the images hold was completed, every
+    /// file of the bundle is named with it
+    pub timestamp: u128,
+    /// Readbacks by frame, complete frames wait here until they are collected, indexed by
+    /// camera id
+    pending: BTreeMap<u32, Vec<Option<Vec<u8>>>>,
+    /// Newest frame any camera delivered
+    newest_readback: Option<u32>,
 }
 
 impl CameraOutputTable {
-    pub fn link_new_target(&mut self, image_handle: Handle<Image>, camera_name: String) -> Sender<Vec<u8>> {
+    /// Registers the cpu image of a camera output, returns the camera id and the sender for its readbacks
+    pub fn link_new_target(&mut self, image_handle: Handle<Image>, camera_name: &str, modality: Modality) -> (usize, Sender<ImageReadback>) {
         
         let (s, r) = crossbeam_channel::unbounded();
+        let output_name = modality.output_name(camera_name);
         
-        match self.camera_names.iter().position(|name| *name == camera_name) {
+        let camera_id = match self.camera_names.iter().position(|name| *name == output_name) {
             Some(index) => {
                 self.image_handles[index] = image_handle;
                 self.receivers[index] = r;
+                index
             },
             None => {
-                self.camera_names.push(camera_name);
+                self.camera_names.push(output_name);
+                self.sources.push(camera_name.to_string());
+                self.modalities.push(modality);
                 self.image_handles.push(image_handle);
                 self.receivers.push(r);
+                self.camera_names.len() - 1
             },
+        };
+
+        (camera_id, s)
+    }
+
+    /// Drops everything received so far
+    pub fn clear_receivers(&mut self) {
+        for receiver in self.receivers.iter() {
+            while receiver.try_recv().is_ok() {}
         }
+        self.pending.clear();
+        self.newest_readback = None;
+    }
+
+    /// Receives the readbacks sent so far. Returns the frames that will never complete, e.g.
+    /// because every staging buffer of a camera was in flight when it was rendered.
+    pub fn receive_readbacks(&mut self) -> Vec<SegmentationError> {
+        let camera_count = self.camera_names.len();
 
-        s
+        // We don't want to block the main world on this,
+        // so we use try_recv which attempts to receive without blocking
+        for receiver in self.receivers.iter() {
+            while let Ok(readback) = receiver.try_recv() {
+                self.newest_readback = self.newest_readback.max(Some(readback.frame));
+                let frame = self.pending
+                    .entry(readback.frame)
+                    .or_insert_with(|| vec![None; camera_count]);
+                frame.resize(camera_count, None);
+                frame[readback.camera_id] = Some(readback.data);
+            }
+        }
+
+        let Some(newest) = self.newest_readback else {
+            return Vec::new();
+        };
+        let is_complete =
+            |data: &Vec<Option<Vec<u8>>>| data.len() == camera_count && data.iter().all(Option::is_some);
+        let stale: Vec<u32> = self.pending
+            .iter()
+            .filter(|(frame, data)| **frame + MAX_PENDING_FRAMES < newest && !is_complete(data))
+            .map(|(frame, _)| *frame)
+            .collect();
+
+        stale
+            .into_iter()
+            .filter_map(|frame| {
+                let data = self.pending.remove(&frame)?;
+                let missing = self.camera_names
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| !matches!(data.get(*i), Some(Some(_))))
+                    .map(|(_, name)| name.clone())
+                    .collect();
+                Some(SegmentationError::IncompleteFrame { frame, missing })
+            })
+            .collect()
+    }
+
+    /// Returns the oldest frame every camera delivered, with the data of each camera in camera id
+    /// order. Frames completed in the same update are returned by the following calls.
+    pub fn collect_frame_bundle(&mut self) -> Option<(u32, Vec<Vec<u8>>)> {
+        let camera_count = self.camera_names.len();
+        let complete = *self.pending
+            .iter()
+            .find(|(_, data)| data.len() == camera_count && data.iter().all(Option::is_some))?
+            .0;
+        let data = self.pending.remove(&complete)?;
+
+        Some((complete, data.into_iter().flatten().collect()))
+    }
+
+    /// CPU image receiving the output of a camera
+    pub fn image_handle(&self, camera_name: &str) -> Option<&Handle<Image>> {
+        self.camera_names
+            .iter()
+            .position(|name| name == camera_name)
+            .map(|index| &self.image_handles[index])
     }
 
     /// Setups render target and cpu image for saving, changes scene state into render mode
@@ -67,72 +173,170 @@ impl CameraOutputTable {
         images: &mut ResMut<Assets<Image>>,
         render_device: &Res<RenderDevice>,
     ) -> RenderTarget {
+        self.create_output_target(
+            &camera_name,
+            Modality::Rgb,
+            width,
+            height,
+            TextureFormat::bevy_default(),
+            commands,
+            images,
+            render_device,
+        )
+    }
+
+    /// Same as `create_render_target` for any modality of `camera_name`, the target and cpu
+    /// image use `format`
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_output_target(
+        &mut self,
+        camera_name: &str,
+        modality: Modality,
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+        commands: &mut Commands,
+        images: &mut ResMut<Assets<Image>>,
+        render_device: &Res<RenderDevice>,
+    ) -> RenderTarget {
+        // bevy uploads the data of every image, it has to cover every texel of the whole texture
+        let texel_size = format
+            .block_copy_size(None)
+            .filter(|_| format.block_dimensions() == (1, 1));
+        let (format, texel_size) = match texel_size {
+            Some(texel_size) => (format, texel_size),
+            None => {
+                let fallback = TextureFormat::bevy_default();
+                error!(
+                    "Format {:?} can not be rendered to, {} is rendered as {:?}",
+                    format,
+                    modality.output_name(camera_name),
+                    fallback
+                );
+                (fallback, fallback.pixel_size() as u32)
+            }
+        };
 
         let size = Extent3d {
-            width: width,
-            height: height,
+            width,
+            height,
             ..Default::default()
         };
 
-        // This is the texture that will be rendered to.
-        let mut render_target_image = Image::new_fill(
-            size,
-            TextureDimension::D2,
-            &[0; 4],
-            TextureFormat::bevy_default(),
-            RenderAssetUsages::default(),
-        );
+        let layout = ReadbackLayout::new(width, height, format);
+
+        // This is the texture that will be rendered to, zeroed since images are always uploaded
+        let data = vec![0; width as usize * height as usize * texel_size as usize];
+        let mut render_target_image = output_image(size, format, data);
         render_target_image.texture_descriptor.usage |=
             TextureUsages::COPY_SRC | TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING;
         let render_target_image_handle = images.add(render_target_image);
 
-        // This is the texture that will be copied to.
-        let cpu_image = Image::new_fill(
-            size,
-            TextureDimension::D2,
-            &[0; 4],
-            TextureFormat::bevy_default(),
-            RenderAssetUsages::default(),
-        );
+        let Some(layout) = layout else {
+            error!(
+                "Format {:?} can not be read back, {} is not captured",
+                format,
+                modality.output_name(camera_name)
+            );
+            return RenderTarget::Image(render_target_image_handle);
+        };
+
+        // This is the texture that will be copied to, it holds what is read back, e.g. only the
+        // depth of a depth stencil format, so it never goes back to the gpu
+        let mut cpu_image = output_image(size, format, vec![0; layout.image_size()]);
+        cpu_image.asset_usage = RenderAssetUsages::MAIN_WORLD;
         let cpu_image_handle = images.add(cpu_image);
 
-        let sender = self.link_new_target(cpu_image_handle, camera_name);
+        let (camera_id, sender) = self.link_new_target(cpu_image_handle, camera_name, modality);
 
         commands.spawn(ImageCopier::new(
+            camera_id,
             sender,
             render_target_image_handle.clone(),
-            size,
+            layout,
             render_device,
         ));
 
         RenderTarget::Image(render_target_image_handle)
     }
 
-    pub fn save_images_to_file(&self, images: &mut ResMut<Assets<Image>>) {
+    /// Queues every image on the `ImageWriter`, conversion and encoding happen in the background.
+    /// Returns the images that could not be queued.
+    pub fn save_images_to_file(
+        &self,
+        images: &Assets<Image>,
+        writer: &mut ImageWriter,
+        output: &OutputConfig,
+    ) -> Vec<SegmentationError> {
 
-        for (image, name) in self.image_handles.iter().zip(self.camera_names.iter()){
+        let mut failures = Vec::new();
+        let (Some(index), Some(frame)) = (self.capture_index, self.frame) else {
+            return failures;
+        };
+
+        for (i, image) in self.image_handles.iter().enumerate() {
 
-            let img_bytes = images.get_mut(image.id()).unwrap();
+            // The table's images are overwritten by the next bundle, the task gets its own copy
+            let Some(img_bytes) = images.get(image.id()).cloned() else {
+                failures.push(SegmentationError::MissingImage { camera: self.camera_names[i].clone() });
+                continue;
+            };
 
-            // Create RGBA Image Buffer. Only for saving to file
-            let img = match img_bytes.clone().try_into_dynamic() {
-                Ok(img) => img.to_rgba8(),
-                Err(e) => panic!("Failed to create image buffer {e:?}"),
+            // e.g. raw depth is only consumed by derived outputs unless asked for
+            let modality = self.modalities[i];
+            let Some(encoding) = output.encoding(modality, img_bytes.texture_descriptor.format) else {
+                continue;
             };
-    
-            // Prepare directory for images, test_images in bevy folder is used here for example
-            // You should choose the path depending on your needs
-            let images_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_images");
-            std::fs::create_dir_all(&images_dir).unwrap();
-    
-            // Choose filename starting from 000.png
-            let image_path = images_dir.join(format!("{:?}.png", name.clone()));
-    
-            // Finally saving image to file, this heavy blocking operation is kept here
-            // for example simplicity, but in real app you should move it to a separate task
-            if let Err(e) = img.save(image_path) {
-                panic!("Failed to save image: {}", e);
+
+            let path = |extension| {
+                output.file_path(
+                    self.split,
+                    &self.sources[i],
+                    modality,
+                    index,
+                    frame,
+                    self.timestamp,
+                    extension,
+                )
             };
+            let image_path = path(encoding.extension());
+            // depth is no radiance, it has no preview
+            let preview = (encoding == Encoding::Exr && output.hdr_previews && modality != Modality::Depth)
+                .then(|| (path("png"), output.preview_exposure));
+
+            writer.write(self.camera_names[i].clone(), move || {
+                create_parent_dir(&image_path)?;
+                save_image(&img_bytes, modality, encoding, &image_path)?;
+
+                if let Some((preview_path, exposure)) = &preview {
+                    tonemapped_preview(&img_bytes, *exposure)
+                        .ok_or_else(|| SegmentationError::encode(preview_path, "unsupported format"))?
+                        .save(preview_path)
+                        .map_err(|e| SegmentationError::encode(preview_path, e))?;
+                }
+                Ok(())
+            });
         }
+
+        failures
     }
-}
\ No newline at end of file
+}
+
+/// Prepares the directory of `path`, the file name pattern may contain subdirectories
+pub(crate) fn create_parent_dir(path: &Path) -> Result<(), SegmentationError> {
+    match path.parent() {
+        Some(dir) => std::fs::create_dir_all(dir).map_err(|e| SegmentationError::io(dir, e)),
+        None => Ok(()),
+    }
+}
+
+/// Image of any format, `Image::new_fill` only handles formats with a plain texel size
+fn output_image(size: Extent3d, format: TextureFormat, data: Vec<u8>) -> Image {
+    let mut image = Image {
+        data,
+        ..default()
+    };
+    image.texture_descriptor.size = size;
+    image.texture_descriptor.format = format;
+    image
+}
@@ -0,0 +1,93 @@
+//! Capture Policy
+//!
+//! Decides which frames are copied back from the GPU and saved. Cameras only pay for readback on
+//! frames the policy selects, a `CaptureRequest` selects the current frame under any policy.
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{
+    input::CaptureRequest,
+    resources::CaptureReadiness,
+    utils::{image_copy::ImageCopier, image_writer::ImageWriter},
+};
+
+#[derive(Resource, Clone, Debug, Default, Deserialize, Serialize)]
+pub enum CapturePolicy {
+    /// Only frames with a `CaptureRequest`
+    #[default]
+    OnDemand,
+    /// Every n-th frame once the scene is ready
+    EveryNFrames(u32),
+    /// Whenever this much simulated (virtual) time passed
+    FixedInterval(#[serde(with = "crate::job::seconds")] Duration),
+    /// Every frame
+    Continuous,
+}
+
+impl CapturePolicy {
+    /// Steps of the `SegmentationRng` between two captures, for policies where that is fixed.
+    /// Only those can be split into shards or resumed, the step of a capture index is known.
+    pub fn steps_per_capture(&self) -> Option<u32> {
+        match self {
+            CapturePolicy::EveryNFrames(n) => Some((*n).max(1)),
+            CapturePolicy::Continuous => Some(1),
+            CapturePolicy::OnDemand | CapturePolicy::FixedInterval(_) => None,
+        }
+    }
+}
+
+/// Progress of the `CapturePolicy` since the scene became ready
+#[derive(Resource, Default)]
+pub(crate) struct CaptureSchedule {
+    frames: u32,
+    next_capture: Option<Duration>,
+}
+
+/// Enables the `ImageCopier`s on frames selected by the `CapturePolicy`
+pub(crate) fn apply_capture_policy(
+    policy: Res<CapturePolicy>,
+    readiness: Res<CaptureReadiness>,
+    time: Res<Time>,
+    mut schedule: ResMut<CaptureSchedule>,
+    mut capture_requests: EventReader<CaptureRequest>,
+    writer: Res<ImageWriter>,
+    copier_query: Query<&ImageCopier>,
+) {
+    let requested = capture_requests.read().count() > 0;
+
+    // a held simulation renders the same step again, it is neither counted nor captured
+    if writer.is_slowing_simulation() {
+        for image_copier in copier_query.iter() {
+            image_copier.set_enabled(readiness.is_ready() && requested);
+        }
+        return;
+    }
+
+    let capture = readiness.is_ready() && {
+        let scheduled = match *policy {
+            CapturePolicy::OnDemand => false,
+            CapturePolicy::EveryNFrames(n) => schedule.frames % n.max(1) == 0,
+            CapturePolicy::FixedInterval(interval) => {
+                let next_capture = schedule.next_capture.get_or_insert(time.elapsed());
+                if time.elapsed() >= *next_capture {
+                    // stay on the interval grid even if frames are longer than the interval
+                    while *next_capture <= time.elapsed() {
+                        *next_capture += interval.max(Duration::from_nanos(1));
+                    }
+                    true
+                } else {
+                    false
+                }
+            }
+            CapturePolicy::Continuous => true,
+        };
+        schedule.frames += 1;
+        scheduled || requested
+    };
+
+    for image_copier in copier_query.iter() {
+        image_copier.set_enabled(capture);
+    }
+}
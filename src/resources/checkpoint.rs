@@ -0,0 +1,195 @@
+//! Checkpoint
+//!
+//! Long jobs save a checkpoint next to their captures: the next capture index, the RNG step it
+//! is rendered at and the state exporters need to continue their files. A resumed run starts
+//! from there, and since the seeded streams only depend on `(seed, step)`, it produces the same
+//! frames the interrupted run would have.
+//!
+//! A `Shard` splits the captures of a job into disjoint index ranges, so several processes can
+//! write one dataset into the same run directory.
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{fmt, ops::Range, path::PathBuf, str::FromStr};
+
+use crate::{
+    error::{CaptureFailed, SegmentationError},
+    resources::{camera_table, FrameBundle, OutputConfig},
+    utils::image_writer::ImageWriter,
+};
+
+/// Shard `index` of `count`, written `index/count` with `index` counted from 0
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Shard {
+    pub index: u32,
+    pub count: u32,
+}
+
+impl Shard {
+    /// Capture indices of this shard out of `frames`, shard sizes differ by at most one
+    pub fn range(&self, frames: u32) -> Range<u32> {
+        let bound = |shard: u32| (frames as u64 * shard as u64 / self.count as u64) as u32;
+        bound(self.index)..bound(self.index + 1)
+    }
+
+    /// Appended to the names of files every shard of a run keeps its own of, e.g. `_0of4`
+    pub fn file_suffix(shard: Option<Shard>) -> String {
+        shard.map_or_else(String::new, |shard| format!("_{}of{}", shard.index, shard.count))
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid shard '{}', expected i/N with i < N", s);
+        let (index, count) = s.split_once('/').ok_or_else(invalid)?;
+        let shard = Shard {
+            index: index.trim().parse().map_err(|_| invalid())?,
+            count: count.trim().parse().map_err(|_| invalid())?,
+        };
+
+        if shard.index < shard.count {
+            Ok(shard)
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Checkpoint {
+    pub seed: u64,
+    pub shard: Option<Shard>,
+    /// Index the next capture is saved with
+    pub next_index: u32,
+    /// RNG step the next capture is rendered at
+    pub next_step: u32,
+    /// See `ExporterState`
+    #[serde(default)]
+    pub exporters: Map<String, Value>,
+}
+
+impl Checkpoint {
+    /// Checkpoint of a run, every shard keeps its own
+    pub fn path(output: &OutputConfig, shard: Option<Shard>) -> PathBuf {
+        output.run_dir().join(format!("checkpoint{}.json", Shard::file_suffix(shard)))
+    }
+
+    /// `None` if there is no checkpoint at `path`
+    pub fn load(path: &std::path::Path) -> Result<Option<Self>, SegmentationError> {
+        match std::fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map(Some)
+                .map_err(|e| SegmentationError::InvalidCheckpoint { path: path.to_path_buf(), message: e.to_string() }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(SegmentationError::InvalidCheckpoint { path: path.to_path_buf(), message: e.to_string() }),
+        }
+    }
+
+    /// An interrupted save keeps the last checkpoint
+    pub fn save(&self, path: &std::path::Path) -> Result<(), SegmentationError> {
+        let json = serde_json::to_vec_pretty(self).map_err(|e| SegmentationError::encode(path, e))?;
+        write_atomically(path, &json)
+    }
+}
+
+/// Writes to a temporary file first, readers see either the old or the new content
+pub(crate) fn write_atomically(path: &std::path::Path, contents: &[u8]) -> Result<(), SegmentationError> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    camera_table::create_parent_dir(path)?;
+    std::fs::write(&temporary, contents).map_err(|e| SegmentationError::io(&temporary, e))?;
+    std::fs::rename(&temporary, path).map_err(|e| SegmentationError::io(path, e))
+}
+
+/// Where the captures of this process start, set when resuming or sharding
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct RunOffset {
+    /// Index of the first capture
+    pub index: u32,
+    /// RNG step of the first capture
+    pub step: u32,
+}
+
+impl RunOffset {
+    /// Index of the capture rendered in `step`. It only depends on the step, so a dropped frame
+    /// leaves a gap instead of renumbering the captures after it.
+    pub fn capture_index(&self, step: u32, steps_per_capture: u32) -> u32 {
+        self.index + step.saturating_sub(self.step) / steps_per_capture.max(1)
+    }
+}
+
+/// State exporters keep across a resume, e.g. the entries of a manifest written so far. Each
+/// exporter owns one key, it is saved with every checkpoint and restored when resuming.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct ExporterState(pub Map<String, Value>);
+
+/// Saves a `Checkpoint` every `interval` captures and after the last one, once their writes
+/// are on disk
+#[derive(Resource, Clone, Debug)]
+pub struct CheckpointConfig {
+    pub path: PathBuf,
+    pub shard: Option<Shard>,
+    pub interval: u32,
+    /// See `CapturePolicy::steps_per_capture`
+    pub steps_per_capture: u32,
+    /// Index after the last capture of this process
+    pub end_index: u32,
+}
+
+/// Index and RNG step of the last saved capture, from its `FrameMetadata`
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct CaptureProgress {
+    pub last: Option<(u32, u32)>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn save_checkpoint(
+    mut frame_bundles: EventReader<FrameBundle>,
+    config: Option<Res<CheckpointConfig>>,
+    progress: Res<CaptureProgress>,
+    exporters: Res<ExporterState>,
+    output: Res<OutputConfig>,
+    mut writer: ResMut<ImageWriter>,
+    mut failures: EventWriter<CaptureFailed>,
+    mut since_checkpoint: Local<u32>,
+) {
+    let bundles = frame_bundles.read().count() as u32;
+    let (Some(config), Some((index, step))) = (config, progress.last) else {
+        return;
+    };
+    if bundles == 0 {
+        return;
+    }
+
+    *since_checkpoint += bundles;
+    let next_index = index + 1;
+    if *since_checkpoint < config.interval.max(1) && next_index < config.end_index {
+        return;
+    }
+    *since_checkpoint = 0;
+
+    // a checkpoint must not claim captures that are still being written
+    writer.flush();
+
+    let checkpoint = Checkpoint {
+        seed: output.seed,
+        shard: config.shard,
+        next_index,
+        next_step: step + config.steps_per_capture,
+        exporters: exporters.0.clone(),
+    };
+    if let Err(error) = checkpoint.save(&config.path) {
+        failures.send(CaptureFailed { error });
+    }
+}
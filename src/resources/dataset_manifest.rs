@@ -0,0 +1,329 @@
+//! Dataset Manifest
+//!
+//! `dataset.json` in the run directory describes the dataset for loaders that do not know this
+//! crate: the plugin version and seed, every camera with its pinhole intrinsics, the label table
+//! with the mask color of each class, the saved outputs with their encodings, and how many frames
+//! were captured. It is written in the background at the first capture, every checkpoint interval,
+//! after the last capture and on `AppExit`, so it also describes an interrupted run, and every
+//! shard of a run keeps its own.
+
+use bevy::{ecs::system::SystemParam, prelude::*, render::render_resource::TextureFormat};
+use serde::Serialize;
+use std::path::PathBuf;
+
+use crate::{
+    components::{CameraDescription, Modality, RGBCamera, StereoRig},
+    error::SegmentationError,
+    resources::{
+        checkpoint::write_atomically, output_config::unix_millis, CameraOutputTable, CheckpointConfig,
+        ExporterState, FrameBundle, OutputConfig, RunOffset, SegmentationDataTable, Shard, SplitPolicy,
+    },
+    utils::image_writer::ImageWriter,
+};
+
+/// Key of the first capture index in the `ExporterState`
+const EXPORTER_KEY: &str = "dataset";
+
+/// Captures between two manifests of runs without a `CheckpointConfig`
+const DEFAULT_INTERVAL: u32 = 100;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DatasetManifest {
+    /// Version of this crate
+    pub version: String,
+    pub run: Option<String>,
+    pub seed: u64,
+    pub shard: Option<Shard>,
+    /// Captures in the run directory, indices `first_index..first_index + frames`
+    pub frames: u32,
+    pub first_index: u32,
+    /// Milliseconds since the unix epoch when the run started
+    pub timestamp: u64,
+    /// See `OutputConfig`
+    pub filename_pattern: String,
+    pub split: Option<SplitPolicy>,
+    pub cameras: Vec<CameraManifest>,
+    pub stereo_rigs: Vec<StereoRigManifest>,
+    /// Segmentation classes, `id` is the position in the table
+    pub labels: Vec<LabelManifest>,
+    pub outputs: Vec<OutputManifest>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CameraManifest {
+    #[serde(flatten)]
+    pub description: CameraDescription,
+    /// `None` for orthographic cameras
+    pub intrinsics: Option<Intrinsics>,
+}
+
+/// Pinhole intrinsics in pixels, the origin is the top left corner of the image
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Intrinsics {
+    pub fx: f32,
+    pub fy: f32,
+    pub cx: f32,
+    pub cy: f32,
+}
+
+impl Intrinsics {
+    pub fn from_projection(projection: &Projection, width: u32, height: u32) -> Option<Self> {
+        let Projection::Perspective(perspective) = projection else {
+            return None;
+        };
+
+        // bevy keeps pixels square, the vertical field of view fixes both focal lengths
+        let focal_length = 0.5 * height as f32 / (0.5 * perspective.fov).tan();
+        Some(Intrinsics {
+            fx: focal_length,
+            fy: focal_length,
+            cx: 0.5 * width as f32,
+            cy: 0.5 * height as f32,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StereoRigManifest {
+    pub name: String,
+    pub left: String,
+    pub right: String,
+    /// Meters between the optical centers
+    pub baseline: f32,
+    /// Pixels, disparity maps hold `focal_length * baseline / depth * 256`
+    pub focal_length: f32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LabelManifest {
+    pub id: usize,
+    pub label: String,
+    /// sRGBA of the class in segmentation masks
+    pub color: [u8; 4],
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct OutputManifest {
+    /// `{camera}` of the file names
+    pub camera: String,
+    pub modality: Modality,
+    /// Texture format the output is rendered in, `None` for derived outputs
+    pub format: Option<String>,
+    /// See `Encoding`
+    pub encoding: String,
+    pub extension: String,
+}
+
+/// Path of the manifest, shards of a run each keep their own
+pub fn manifest_path(output: &OutputConfig, shard: Option<Shard>) -> PathBuf {
+    output.run_dir().join(format!("dataset{}.json", Shard::file_suffix(shard)))
+}
+
+/// When the run started and what the last written manifest is missing
+#[derive(Resource, Clone, Debug)]
+pub(crate) struct ManifestProgress {
+    started: u64,
+    /// Index of the last capture
+    last_index: Option<u32>,
+    /// Captures since the last manifest was queued
+    since_write: u32,
+    written: bool,
+}
+
+impl Default for ManifestProgress {
+    fn default() -> Self {
+        ManifestProgress {
+            started: unix_millis() as u64,
+            last_index: None,
+            since_write: 0,
+            written: false,
+        }
+    }
+}
+
+/// Everything the manifest describes
+#[derive(SystemParam)]
+pub(crate) struct ManifestSources<'w, 's> {
+    image_table: Res<'w, CameraOutputTable>,
+    data_table: Res<'w, SegmentationDataTable>,
+    images: Res<'w, Assets<Image>>,
+    output: Res<'w, OutputConfig>,
+    offset: Res<'w, RunOffset>,
+    config: Option<Res<'w, CheckpointConfig>>,
+    exporters: ResMut<'w, ExporterState>,
+    camera_query: Query<'w, 's, (&'static RGBCamera, Option<&'static Projection>)>,
+    rig_query: Query<'w, 's, &'static StereoRig>,
+}
+
+impl ManifestSources<'_, '_> {
+    /// Index of the first capture in the run directory, resumed runs continue the count of the
+    /// run they resume
+    fn first_index(&mut self) -> u32 {
+        let offset = self.offset.index;
+        self.exporters
+            .0
+            .entry(EXPORTER_KEY)
+            .or_insert_with(|| offset.into())
+            .as_u64()
+            .map_or(offset, |first_index| first_index as u32)
+    }
+
+    fn manifest(&mut self, last_index: u32, started: u64) -> DatasetManifest {
+        let first_index = self.first_index();
+
+        let mut cameras: Vec<CameraManifest> = self
+            .camera_query
+            .iter()
+            .map(|(camera, projection)| CameraManifest {
+                description: camera.0.clone(),
+                intrinsics: projection
+                    .and_then(|projection| Intrinsics::from_projection(projection, camera.width, camera.height)),
+            })
+            .collect();
+        cameras.sort_by(|a, b| a.description.name.cmp(&b.description.name));
+
+        let stereo_rigs = self
+            .rig_query
+            .iter()
+            .map(|rig| StereoRigManifest {
+                name: rig.description.name.clone(),
+                left: rig.left_name(),
+                right: rig.right_name(),
+                baseline: rig.baseline,
+                focal_length: rig.focal_length(),
+            })
+            .collect();
+
+        let labels = self
+            .data_table
+            .classes()
+            .enumerate()
+            .map(|(id, (label, color))| LabelManifest {
+                id,
+                label: label.clone(),
+                color: color.to_srgba().to_u8_array(),
+            })
+            .collect();
+
+        let image_table = &self.image_table;
+        let mut outputs: Vec<OutputManifest> = image_table
+            .image_handles
+            .iter()
+            .enumerate()
+            .filter_map(|(i, handle)| {
+                let format = self.images.get(handle)?.texture_descriptor.format;
+                output_manifest(&self.output, &image_table.sources[i], image_table.modalities[i], Some(format))
+            })
+            .collect();
+        outputs.extend(
+            self.rig_query
+                .iter()
+                .filter_map(|rig| output_manifest(&self.output, &rig.description.name, Modality::Disparity, None)),
+        );
+        outputs.push(OutputManifest {
+            camera: "frame".to_string(),
+            modality: Modality::Metadata,
+            format: None,
+            encoding: "json".to_string(),
+            extension: "json".to_string(),
+        });
+
+        DatasetManifest {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            run: self.output.run_name.clone(),
+            seed: self.output.seed,
+            shard: self.shard(),
+            frames: (last_index + 1).saturating_sub(first_index),
+            first_index,
+            timestamp: started,
+            filename_pattern: self.output.filename_pattern.clone(),
+            split: self.output.split.clone(),
+            cameras,
+            stereo_rigs,
+            labels,
+            outputs,
+        }
+    }
+
+    fn shard(&self) -> Option<Shard> {
+        self.config.as_ref().and_then(|config| config.shard)
+    }
+}
+
+/// Queues the manifest on the `ImageWriter`, serializing and writing it happen in the background
+fn queue_manifest(manifest: DatasetManifest, path: PathBuf, writer: &mut ImageWriter) {
+    writer.write(path.display().to_string(), move || {
+        let json = serde_json::to_vec_pretty(&manifest).map_err(|e| SegmentationError::encode(&path, e))?;
+        write_atomically(&path, &json)
+    });
+}
+
+/// Writes the manifest at the first capture, every checkpoint interval and after the last capture
+pub(crate) fn save_dataset_manifest(
+    mut frame_bundles: EventReader<FrameBundle>,
+    mut sources: ManifestSources,
+    mut progress: ResMut<ManifestProgress>,
+    mut writer: ResMut<ImageWriter>,
+) {
+    let bundles = frame_bundles.read().count() as u32;
+    if bundles == 0 {
+        return;
+    }
+    let Some(index) = sources.image_table.capture_index else {
+        return;
+    };
+
+    // the first index is saved with every checkpoint, also between two manifests
+    sources.first_index();
+    progress.last_index = Some(index);
+    progress.since_write += bundles;
+
+    let (interval, end_index) = sources
+        .config
+        .as_ref()
+        .map_or((DEFAULT_INTERVAL, u32::MAX), |config| (config.interval.max(1), config.end_index));
+    if progress.written && progress.since_write < interval && index + 1 < end_index {
+        return;
+    }
+
+    let manifest = sources.manifest(index, progress.started);
+    queue_manifest(manifest, manifest_path(&sources.output, sources.shard()), &mut writer);
+    progress.since_write = 0;
+    progress.written = true;
+}
+
+/// Writes the captures since the last manifest before the `ImageWriter` is flushed on `AppExit`
+pub(crate) fn save_dataset_manifest_on_exit(
+    mut sources: ManifestSources,
+    mut progress: ResMut<ManifestProgress>,
+    mut writer: ResMut<ImageWriter>,
+) {
+    let Some(index) = progress.last_index else {
+        return;
+    };
+    if progress.written && progress.since_write == 0 {
+        return;
+    }
+
+    let manifest = sources.manifest(index, progress.started);
+    queue_manifest(manifest, manifest_path(&sources.output, sources.shard()), &mut writer);
+    progress.since_write = 0;
+    progress.written = true;
+}
+
+fn output_manifest(
+    output: &OutputConfig,
+    camera: &str,
+    modality: Modality,
+    format: Option<TextureFormat>,
+) -> Option<OutputManifest> {
+    // derived outputs are encoded from their own formats, e.g. 16 bit disparity
+    let encoding = output.encoding(modality, format.unwrap_or(TextureFormat::R16Uint))?;
+    Some(OutputManifest {
+        camera: camera.to_string(),
+        modality,
+        format: format.map(|format| format!("{:?}", format)),
+        encoding: encoding.to_string(),
+        extension: encoding.extension().to_string(),
+    })
+}
@@ -0,0 +1,118 @@
+//! Frame Metadata
+//!
+//! A JSON file saved next to every frame bundle with the seed, frame, capture index and RNG
+//! step, plus whatever systems recorded for that frame, e.g. the parameters a randomizer applied.
+//! Readbacks arrive a few frames after the update that set the scene up, so entries are kept per
+//! frame until their bundle is saved, or until the frame is too old to ever complete.
+//!
+//! The asset paths of the spawned scenes are recorded for every frame under `scenes`, e.g. for
+//! `SplitKey::Scene`.
+
+use bevy::prelude::*;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use crate::{
+    components::Modality,
+    error::{CaptureFailed, SegmentationError},
+    resources::{
+        camera_table, checkpoint::CaptureProgress, CameraOutputTable, FrameBundle, OutputConfig, SegmentationRng,
+    },
+    utils::{image_copy::READBACK_RING_SIZE, image_writer::ImageWriter},
+};
+
+/// Frames this many frames behind the newest entry are dropped, the `CameraOutputTable` gives up
+/// on them earlier
+const MAX_PENDING_FRAMES: u32 = camera_table::MAX_PENDING_FRAMES + 2 * READBACK_RING_SIZE as u32;
+
+#[derive(Resource, Default)]
+pub struct FrameMetadata {
+    frames: BTreeMap<u32, Map<String, Value>>,
+}
+
+impl FrameMetadata {
+    /// Records `value` under `key` for `frame`, usually `SegmentationRng::frame`. Entries of frames
+    /// that were never captured, e.g. under `CapturePolicy::OnDemand`, are dropped on the way.
+    pub fn insert(&mut self, frame: u32, key: &str, value: impl Serialize) {
+        let value = serde_json::to_value(value).unwrap_or_else(|e| Value::String(e.to_string()));
+        self.frames.entry(frame).or_default().insert(key.to_string(), value);
+        self.frames.retain(|pending, _| *pending + MAX_PENDING_FRAMES > frame);
+    }
+
+    pub fn get(&self, frame: u32) -> Option<&Map<String, Value>> {
+        self.frames.get(&frame)
+    }
+
+    fn take(&mut self, frame: u32) -> Map<String, Value> {
+        self.frames.remove(&frame).unwrap_or_default()
+    }
+}
+
+/// Records the sorted asset paths of every `Handle<Scene>` and `Handle<DynamicScene>` entity
+pub(crate) fn record_scenes(
+    asset_server: Res<AssetServer>,
+    rng: Res<SegmentationRng>,
+    mut metadata: ResMut<FrameMetadata>,
+    scene_query: Query<&Handle<Scene>>,
+    dynamic_scene_query: Query<&Handle<DynamicScene>>,
+) {
+    let mut scenes: Vec<String> = scene_query
+        .iter()
+        .filter_map(|handle| asset_server.get_path(handle.id()))
+        .chain(dynamic_scene_query.iter().filter_map(|handle| asset_server.get_path(handle.id())))
+        .map(|path| path.to_string())
+        .collect();
+    scenes.sort();
+    scenes.dedup();
+    metadata.insert(rng.frame(), "scenes", scenes);
+}
+
+pub(crate) fn save_frame_metadata(
+    mut frame_bundles: EventReader<FrameBundle>,
+    image_table: Res<CameraOutputTable>,
+    mut metadata: ResMut<FrameMetadata>,
+    mut progress: ResMut<CaptureProgress>,
+    mut writer: ResMut<ImageWriter>,
+    output: Res<OutputConfig>,
+    mut failures: EventWriter<CaptureFailed>,
+) {
+    if frame_bundles.read().count() == 0 {
+        return;
+    }
+
+    let (Some(index), Some(frame)) = (image_table.capture_index, image_table.frame) else {
+        return;
+    };
+
+    let mut entries = Map::new();
+    entries.insert("seed".to_string(), output.seed.into());
+    entries.insert("frame".to_string(), frame.into());
+    entries.insert("index".to_string(), index.into());
+    entries.extend(metadata.take(frame));
+    if let Some(step) = entries.get("step").and_then(Value::as_u64) {
+        progress.last = Some((index, step as u32));
+    }
+
+    let path = output.file_path(
+        image_table.split,
+        "frame",
+        Modality::Metadata,
+        index,
+        frame,
+        image_table.timestamp,
+        "json",
+    );
+    let json = match serde_json::to_vec_pretty(&Value::Object(entries)) {
+        Ok(json) => json,
+        Err(e) => {
+            failures.send(CaptureFailed { error: SegmentationError::encode(&path, e) });
+            return;
+        }
+    };
+
+    writer.write(format!("frame {} metadata", frame), move || {
+        camera_table::create_parent_dir(&path)?;
+        std::fs::write(&path, &json).map_err(|e| SegmentationError::io(&path, e))
+    });
+}
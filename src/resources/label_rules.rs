@@ -0,0 +1,96 @@
+//! Label Rules
+//!
+//! Labels meshes of loaded scenes (e.g. glTF) by the `Name` of the mesh or one of its ancestors,
+//! so scenes don't need to be annotated in code. Patterns match whole names, `*` matches any run
+//! of characters. The first matching rule wins, the nearest named ancestor is tried first.
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::components::{SegmentationObject, SegmentationTwin, DepthTwin};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LabelRule {
+    /// Name pattern, e.g. `"Car*"`
+    pub pattern: String,
+    /// Class the matching meshes get
+    pub label: String,
+}
+
+#[derive(Resource, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LabelRules(pub Vec<LabelRule>);
+
+impl LabelRules {
+    /// Parses a RON list of rules, e.g. `[(pattern: "Car*", label: "car")]`
+    pub fn from_ron(source: &str) -> Result<Self, ron::error::SpannedError> {
+        ron::from_str(source)
+    }
+
+    /// Label of the first rule matching `name`
+    pub fn label_of(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|rule| matches_pattern(&rule.pattern, name))
+            .map(|rule| rule.label.as_str())
+    }
+}
+
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // no wildcard
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Gives unlabeled meshes a `SegmentationObject` from the `LabelRules`
+#[allow(clippy::type_complexity)]
+pub(crate) fn apply_label_rules(
+    mut commands: Commands,
+    rules: Res<LabelRules>,
+    mesh_query: Query<
+        (Entity, Option<&Name>),
+        (
+            With<Handle<Mesh>>,
+            Without<SegmentationObject>,
+            Without<SegmentationTwin>,
+            Without<DepthTwin>,
+        ),
+    >,
+    new_meshes: Query<(), Added<Handle<Mesh>>>,
+    parent_query: Query<&Parent>,
+    name_query: Query<&Name>,
+) {
+    if !rules.is_changed() && new_meshes.is_empty() {
+        return;
+    }
+
+    for (entity, name) in mesh_query.iter() {
+        let label = name
+            .and_then(|name| rules.label_of(name))
+            .or_else(|| {
+                parent_query
+                    .iter_ancestors(entity)
+                    .filter_map(|ancestor| name_query.get(ancestor).ok())
+                    .find_map(|name| rules.label_of(name))
+            });
+
+        if let Some(label) = label {
+            commands.entity(entity).insert(SegmentationObject::from(label));
+        }
+    }
+}
@@ -1,89 +1,222 @@
-use bevy::{
-    prelude::*,
-    render::{
-        renderer::RenderDevice,
-        texture::TextureFormatPixelInfo,
-    }
-};
+use bevy::{app::AppExit, prelude::*, render::render_resource::TextureFormat};
 
 pub mod object_table;
 pub mod camera_table;
+pub mod capture_policy;
+pub mod checkpoint;
+pub mod dataset_manifest;
+pub mod frame_metadata;
+pub mod label_rules;
+pub mod output_config;
+pub mod readiness;
+pub mod rng;
+pub mod split;
 
-pub use camera_table::CameraOutputTable;
+pub use camera_table::{CameraOutputTable, FrameBundle};
+pub use capture_policy::CapturePolicy;
+pub use checkpoint::{Checkpoint, CheckpointConfig, ExporterState, RunOffset, Shard};
+pub use dataset_manifest::DatasetManifest;
+pub use frame_metadata::FrameMetadata;
+pub use label_rules::{LabelRule, LabelRules};
+pub use output_config::OutputConfig;
+pub use readiness::CaptureReadiness;
+pub use rng::SegmentationRng;
+pub use split::{Split, SplitKey, SplitPolicy};
 pub use object_table::SegmentationDataTable;
 
 
-use crate::utils::image_copy::*;
+use crate::{
+    components::{Modality, StereoRig},
+    error::{CaptureErrorPlugin, CaptureFailed, SegmentationError},
+    utils::{
+        encoding::save_disparity,
+        image_copy::*,
+        image_writer::{flush_image_writer, ImageWriter, ImageWriterPlugin},
+    },
+};
 
 /// Setups image saver
 pub struct InternalCameraOutput;
 impl Plugin for InternalCameraOutput {
     fn build(&self, app: &mut App) {
         app
-            .add_systems(PostUpdate, (update_camera_table, save_camera_table_to_file).chain())
-            .add_plugins(ImageCopyPlugin);
+            .init_resource::<CapturePolicy>()
+            .init_resource::<OutputConfig>()
+            .init_resource::<capture_policy::CaptureSchedule>()
+            .init_resource::<SegmentationRng>()
+            .init_resource::<FrameMetadata>()
+            .init_resource::<RunOffset>()
+            .init_resource::<ExporterState>()
+            .init_resource::<checkpoint::CaptureProgress>()
+            .init_resource::<dataset_manifest::ManifestProgress>()
+            .add_event::<FrameBundle>()
+            .add_systems(Startup, split::reset_split_manifests)
+            .add_systems(First, rng::advance_segmentation_rng)
+            .add_systems(PreUpdate, frame_metadata::record_scenes)
+            .add_systems(PostUpdate, (
+                readiness::update_capture_readiness,
+                capture_policy::apply_capture_policy,
+                update_camera_table,
+                split::assign_split,
+                save_camera_table_to_file,
+                save_stereo_disparity_to_file,
+                frame_metadata::save_frame_metadata,
+                dataset_manifest::save_dataset_manifest,
+                checkpoint::save_checkpoint,
+            ).chain().in_set(CaptureSystems))
+            .add_systems(
+                Last,
+                dataset_manifest::save_dataset_manifest_on_exit
+                    .run_if(on_event::<AppExit>())
+                    .before(flush_image_writer),
+            )
+            .add_plugins((
+                CaptureErrorPlugin,
+                ImageCopyPlugin,
+                ImageWriterPlugin,
+                readiness::CaptureReadinessPlugin,
+            ));
     }
 }
 
-// Takes from channel image content sent from render world and saves it to disk
+/// Systems collecting frame bundles and queueing their writes, in `PostUpdate`
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CaptureSystems;
+
+// Takes from channel image content sent from render world and copies complete frames to the table
+#[allow(clippy::too_many_arguments)]
 fn update_camera_table(
     mut image_table: ResMut<CameraOutputTable>,
     mut images: ResMut<Assets<Image>>,
+    mut frame_bundles: EventWriter<FrameBundle>,
+    mut failures: EventWriter<CaptureFailed>,
+    readiness: Res<CaptureReadiness>,
+    offset: Res<RunOffset>,
+    checkpoint: Option<Res<CheckpointConfig>>,
+    metadata: Res<FrameMetadata>,
 ) {
-    if image_table.preroll < 1 {
-        // We don't want to block the main world on this,
-        // so we use try_recv which attempts to receive without blocking
-        for (image, receiver) in image_table.image_handles.iter().zip(image_table.receivers.iter()) {
-            
-            let mut image_data = Vec::new();
-            
-            while let Ok(data) = receiver.try_recv() {
-                // image generation could be faster than saving to fs,
-                // that's why use only last of them
-                image_data = data;
-            }
-
-            if !image_data.is_empty() {
-                // info!("Copying Image to table");
-                // Fill correct data from channel to image
-                let img_bytes = images.get_mut(image.id()).unwrap();
-
-                // We need to ensure that this works regardless of the image dimensions
-                // If the image became wider when copying from the texture to the buffer,
-                // then the data is reduced to its original size when copying from the buffer to the image.
-                let row_bytes = img_bytes.width() as usize
-                    * img_bytes.texture_descriptor.format.pixel_size();
-                let aligned_row_bytes = RenderDevice::align_copy_bytes_per_row(row_bytes);
-                
-                if row_bytes == aligned_row_bytes {
-                    img_bytes.data.clone_from(&image_data);
-                } else {
-                    // shrink data to original image size
-                    img_bytes.data = image_data
-                        .chunks(aligned_row_bytes)
-                        .take(img_bytes.height() as usize)
-                        .flat_map(|row| &row[..row_bytes.min(row.len())])
-                        .cloned()
-                        .collect();
-                }
-            }
-        }
-    } else {
-        // clears channel for skipped frames
-        for receiver in image_table.receivers.iter() {
-            while receiver.try_recv().is_ok() {}
+    if !readiness.is_ready() {
+        // clears channel for frames rendered while the scene is still loading
+        image_table.clear_receivers();
+        return;
+    }
+
+    let incomplete = image_table.receive_readbacks();
+    failures.send_batch(incomplete.into_iter().map(|error| CaptureFailed { error }));
+
+    // at most one frame is rendered per update, so frames completed together are caught up on
+    // over the next updates
+    let Some((frame, bundle)) = image_table.collect_frame_bundle() else {
+        return;
+    };
+
+    let mut complete = true;
+    for (i, image_data) in bundle.into_iter().enumerate() {
+        // Fill correct data from channel to image
+        let Some(img_bytes) = images.get_mut(image_table.image_handles[i].id()) else {
+            failures.send(CaptureFailed {
+                error: SegmentationError::MissingImage { camera: image_table.camera_names[i].clone() },
+            });
+            complete = false;
+            continue;
+        };
+
+        // Readbacks arrive without row padding, in the layout of the cpu image
+        if image_data.len() != img_bytes.data.len() {
+            failures.send(CaptureFailed {
+                error: SegmentationError::SizeMismatch {
+                    camera: image_table.camera_names[i].clone(),
+                    expected: img_bytes.data.len(),
+                    actual: image_data.len(),
+                },
+            });
+            complete = false;
+            continue;
         }
-        image_table.preroll -= 1;
+        img_bytes.data = image_data;
     }
+
+    // A bundle with a stale image is worse than a skipped frame
+    if !complete {
+        return;
+    }
+
+    image_table.frame = Some(frame);
+    image_table.timestamp = output_config::unix_millis();
+    // runs that can be split or resumed number captures by their step, so every shard and resume
+    // agrees on the index of a frame, other runs count bundles
+    let step = metadata.get(frame).and_then(|entries| entries.get("step")?.as_u64());
+    image_table.capture_index = Some(match (checkpoint, step) {
+        (Some(checkpoint), Some(step)) => offset.capture_index(step as u32, checkpoint.steps_per_capture),
+        _ => image_table.capture_index.map_or(offset.index, |index| index + 1),
+    });
+    frame_bundles.send(FrameBundle { frame });
 }
 
+// Only frames selected by the `CapturePolicy` are copied, so every bundle is saved
 fn save_camera_table_to_file(
-    input: Res<ButtonInput<KeyCode>>,
-    image_table: ResMut<CameraOutputTable>,
-    mut images: ResMut<Assets<Image>>,
+    mut frame_bundles: EventReader<FrameBundle>,
+    image_table: Res<CameraOutputTable>,
+    images: Res<Assets<Image>>,
+    mut writer: ResMut<ImageWriter>,
+    output: Res<OutputConfig>,
+    mut failures: EventWriter<CaptureFailed>,
 ) {
-    if input.just_pressed(KeyCode::KeyS) {
-        info!("Saving Camera Table to files");
-        image_table.save_images_to_file(&mut images);
+    if frame_bundles.read().count() > 0 {
+        info!("Saving Camera Table to files, frame {:?}", image_table.frame);
+        let errors = image_table.save_images_to_file(&images, &mut writer, &output);
+        failures.send_batch(errors.into_iter().map(|error| CaptureFailed { error }));
     }
-}
\ No newline at end of file
+}
+
+fn save_stereo_disparity_to_file(
+    mut frame_bundles: EventReader<FrameBundle>,
+    image_table: Res<CameraOutputTable>,
+    images: Res<Assets<Image>>,
+    mut writer: ResMut<ImageWriter>,
+    output: Res<OutputConfig>,
+    rig_query: Query<&StereoRig>,
+    mut failures: EventWriter<CaptureFailed>,
+) {
+    if frame_bundles.read().count() == 0 {
+        return;
+    }
+
+    let (Some(index), Some(frame)) = (image_table.capture_index, image_table.frame) else {
+        return;
+    };
+
+    for rig in rig_query.iter() {
+        let Some(depth) = image_table
+            .image_handle(&rig.depth_name())
+            .and_then(|handle| images.get(handle)) else {
+            failures.send(CaptureFailed {
+                error: SegmentationError::MissingImage { camera: rig.depth_name() },
+            });
+            continue;
+        };
+
+        let Some(encoding) = output.encoding(Modality::Disparity, TextureFormat::R16Uint) else {
+            continue;
+        };
+
+        info!("Saving disparity of {}", rig.description.name);
+
+        let image_path = output.file_path(
+            image_table.split,
+            &rig.description.name,
+            Modality::Disparity,
+            index,
+            frame,
+            image_table.timestamp,
+            encoding.extension(),
+        );
+        let depth = depth.clone();
+        let rig = rig.clone();
+
+        writer.write(rig.disparity_name(), move || {
+            camera_table::create_parent_dir(&image_path)?;
+            save_disparity(rig.disparity_image(&depth)?, encoding, &image_path)
+        });
+    }
+}
@@ -11,15 +11,19 @@ use bevy::{
     color::Color,
     ecs::prelude::Resource
 };
+use rand_chacha::ChaCha8Rng;
 
-use crate::utils::random_color;
+use crate::{resources::SegmentationRng, utils::random_color};
 
 /// SegmentationDataTable
 /// Stores all object names and can generate a unique display color for each. 
 #[derive(Resource)]
 pub struct SegmentationDataTable {
     class_labels: Vec<String>,
-    class_colors: Vec<Color>
+    class_colors: Vec<Color>,
+    /// Colors only depend on the order labels are added, not on the run seed, so every run and
+    /// shard of a job agrees on them
+    color_rng: ChaCha8Rng,
 }
 
 impl SegmentationDataTable {
@@ -31,10 +35,10 @@ impl SegmentationDataTable {
         self.class_colors.iter().position(|c| c == color)
     }
 
-    pub fn new_color(&self) -> Color {
-        let mut new_color = random_color();
-        while self.index_of_color(&new_color) != None {
-            new_color = random_color();
+    pub fn new_color(&mut self) -> Color {
+        let mut new_color = random_color(&mut self.color_rng);
+        while self.index_of_color(&new_color).is_some() {
+            new_color = random_color(&mut self.color_rng);
         };
         new_color
     }
@@ -43,16 +47,22 @@ impl SegmentationDataTable {
         match self.index_of_label(&label) {
             Some(id) => id,
             None => {
+                let color = self.new_color();
                 self.class_labels.push(label);
-                self.class_colors.push(self.new_color());
+                self.class_colors.push(color);
                 self.class_labels.len() - 1
             }
         }
     }
 
+    /// All class labels with their display colors, in index order
+    pub fn classes(&self) -> impl Iterator<Item = (&String, &Color)> {
+        self.class_labels.iter().zip(self.class_colors.iter())
+    }
+
     pub fn color_of_object_assertive(&mut self, label: String) -> Color {
         let index = self.label_id(label);
-        self.class_colors[index].clone()
+        self.class_colors[index]
     }
 
     // fn color_id(&mut self, color: Color) -> Option<usize> {
@@ -83,7 +93,8 @@ impl Default for SegmentationDataTable {
     fn default() -> Self {
         SegmentationDataTable {
             class_labels: vec![String::from("other")],
-            class_colors: vec![Color::srgba(0.0, 0.0, 0.0, 0.0)]
+            class_colors: vec![Color::srgba(0.0, 0.0, 0.0, 0.0)],
+            color_rng: SegmentationRng::new(0).run_stream("class_colors"),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,229 @@
+//! Output Config
+//!
+//! Where captures are written and how the files are named. Every run gets its own subdirectory
+//! under `root`, file names come from a template with the placeholders
+//!
+//! - `{camera}` name of the camera (or rig) the image belongs to
+//! - `{modality}` e.g. `rgb`, `segmentation`, `disparity`
+//! - `{index}` sequence number of the capture within the run, zero padded
+//! - `{frame}` frame the capture was rendered in, zero padded
+//! - `{timestamp}` milliseconds since the unix epoch when the frame bundle was completed, the same
+//!   for every file of the bundle
+//! - `{seed}` seed of the run
+//!
+//! With a `SplitPolicy` the files of a capture go into the folder of its split within the run.
+
+use bevy::{prelude::*, render::render_resource::TextureFormat, utils::HashMap};
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    components::Modality,
+    resources::split::{Split, SplitPolicy},
+    utils::{encoding::Encoding, hdr::is_hdr_format},
+};
+
+#[derive(Resource, Clone, Debug)]
+pub struct OutputConfig {
+    /// Directory all runs are written under
+    pub root: PathBuf,
+    /// Subdirectory of this run, `None` writes straight into `root`
+    pub run_name: Option<String>,
+    /// File name template without extension
+    pub filename_pattern: String,
+    /// Digits `{index}` and `{frame}` are padded to
+    pub index_digits: usize,
+    pub seed: u64,
+    /// Encoding per modality, see `encoding`
+    pub encodings: HashMap<Modality, Encoding>,
+    /// Also save a tonemapped PNG next to every EXR
+    pub hdr_previews: bool,
+    /// Exposure of the previews in stops
+    pub preview_exposure: f32,
+    /// Train, val and test splits, `None` writes every capture into the run directory
+    pub split: Option<SplitPolicy>,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            root: PathBuf::from("segmentation_dataset"),
+            run_name: Some(format!("run_{}", unix_millis() / 1000)),
+            filename_pattern: String::from("{camera}_{modality}_{index}"),
+            index_digits: 6,
+            seed: 0,
+            encodings: HashMap::new(),
+            hdr_previews: false,
+            preview_exposure: 0.0,
+            split: None,
+        }
+    }
+}
+
+impl OutputConfig {
+    pub fn with_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    pub fn with_run_name(mut self, run_name: Option<String>) -> Self {
+        self.run_name = run_name;
+        self
+    }
+
+    pub fn with_filename_pattern(mut self, pattern: &str) -> Self {
+        self.filename_pattern = pattern.to_string();
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_encoding(mut self, modality: Modality, encoding: Encoding) -> Self {
+        self.encodings.insert(modality, encoding);
+        self
+    }
+
+    /// How outputs of `modality` in `format` are saved. Without an entry in `encodings` float
+    /// images become EXR, raw depth is not saved and everything else is PNG.
+    pub fn encoding(&self, modality: Modality, format: TextureFormat) -> Option<Encoding> {
+        match self.encodings.get(&modality) {
+            Some(encoding) => Some(*encoding),
+            None if modality == Modality::Depth => None,
+            None if is_hdr_format(format) => Some(Encoding::Exr),
+            None => Some(Encoding::default()),
+        }
+    }
+
+    pub fn with_hdr_previews(mut self, hdr_previews: bool) -> Self {
+        self.hdr_previews = hdr_previews;
+        self
+    }
+
+    pub fn with_split(mut self, split: Option<SplitPolicy>) -> Self {
+        self.split = split;
+        self
+    }
+
+    /// Directory of this run
+    pub fn run_dir(&self) -> PathBuf {
+        match &self.run_name {
+            Some(run_name) => self.root.join(run_name),
+            None => self.root.clone(),
+        }
+    }
+
+    /// File name of one capture from `filename_pattern`, with `extension` appended. `timestamp` is
+    /// the one of the `FrameBundle`, see `CameraOutputTable::timestamp`.
+    pub fn file_name(
+        &self,
+        camera: &str,
+        modality: Modality,
+        index: u32,
+        frame: u32,
+        timestamp: u128,
+        extension: &str,
+    ) -> String {
+        let name = self
+            .filename_pattern
+            .replace("{camera}", camera)
+            .replace("{modality}", modality.name())
+            .replace("{index}", &format!("{:0width$}", index, width = self.index_digits))
+            .replace("{frame}", &format!("{:0width$}", frame, width = self.index_digits))
+            .replace("{timestamp}", &timestamp.to_string())
+            .replace("{seed}", &self.seed.to_string());
+
+        format!("{}.{}", name, extension)
+    }
+
+    /// Full path of one capture in the folder of its `split`, see `file_name`
+    #[allow(clippy::too_many_arguments)]
+    pub fn file_path(
+        &self,
+        split: Option<Split>,
+        camera: &str,
+        modality: Modality,
+        index: u32,
+        frame: u32,
+        timestamp: u128,
+        extension: &str,
+    ) -> PathBuf {
+        let dir = match split {
+            Some(split) => self.run_dir().join(split.name()),
+            None => self.run_dir(),
+        };
+        dir.join(self.file_name(camera, modality, index, frame, timestamp, extension))
+    }
+}
+
+pub(crate) fn unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_pattern(pattern: &str) -> OutputConfig {
+        OutputConfig::default()
+            .with_root("dataset")
+            .with_run_name(Some("run".to_string()))
+            .with_filename_pattern(pattern)
+            .with_seed(42)
+    }
+
+    #[test]
+    fn placeholders_are_expanded() {
+        let config = with_pattern("{seed}/{camera}_{modality}_{index}_{frame}_{timestamp}");
+        assert_eq!(
+            config.file_name("left", Modality::Segmentation, 7, 1234, 1_700_000_000_000, "png"),
+            "42/left_segmentation_000007_001234_1700000000000.png"
+        );
+
+        // placeholders can repeat, text around them is kept
+        let config = with_pattern("cam-{camera}-{camera}");
+        assert_eq!(config.file_name("rig", Modality::Rgb, 0, 0, 0, "exr"), "cam-rig-rig.exr");
+    }
+
+    #[test]
+    fn index_and_frame_are_zero_padded() {
+        let mut config = with_pattern("{index}_{frame}");
+        config.index_digits = 3;
+        assert_eq!(config.file_name("camera", Modality::Rgb, 5, 42, 0, "png"), "005_042.png");
+        // numbers wider than the padding are not cut
+        assert_eq!(config.file_name("camera", Modality::Rgb, 12345, 1000, 0, "png"), "12345_1000.png");
+
+        config.index_digits = 0;
+        assert_eq!(config.file_name("camera", Modality::Rgb, 5, 42, 0, "png"), "5_42.png");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_kept() {
+        let config = with_pattern("{camera}_{unknown}_{Index}_{index");
+        assert_eq!(config.file_name("camera", Modality::Rgb, 1, 0, 0, "png"), "camera_{unknown}_{Index}_{index.png");
+    }
+
+    #[test]
+    fn file_paths_go_into_the_run_and_split() {
+        let config = with_pattern("{camera}_{index}");
+        assert_eq!(
+            config.file_path(None, "camera", Modality::Rgb, 1, 0, 0, "png"),
+            PathBuf::from("dataset/run/camera_000001.png")
+        );
+        assert_eq!(
+            config.file_path(Some(Split::Val), "camera", Modality::Rgb, 1, 0, 0, "png"),
+            PathBuf::from("dataset/run/val/camera_000001.png")
+        );
+        assert_eq!(
+            config.with_run_name(None).file_path(None, "camera", Modality::Rgb, 1, 0, 0, "png"),
+            PathBuf::from("dataset/camera_000001.png")
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Capture Readiness
+//!
+//! Decides when the scene is ready to be captured. Readbacks are discarded until the tracked
+//! assets and every `Handle<Scene>` are loaded with their dependencies, all scene instances are
+//! spawned and the render world went a few frames without compiling or adding pipelines.
+//! `timeout_frames` is only a fallback for scenes that never settle, e.g. because an asset failed
+//! to load.
+
+use bevy::{
+    asset::{RecursiveDependencyLoadState, UntypedHandle},
+    prelude::*,
+    render::{render_resource::PipelineCache, Render, RenderApp, RenderSet},
+    scene::{SceneInstance, SceneSpawner},
+};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Tracks readiness in the main world and counts idle frames in the render world
+pub struct CaptureReadinessPlugin;
+
+impl Plugin for CaptureReadinessPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CaptureReadiness>();
+
+        let render_idle_frames = app
+            .world()
+            .resource::<CaptureReadiness>()
+            .render_idle_frames
+            .clone();
+
+        app.sub_app_mut(RenderApp)
+            .insert_resource(RenderIdleFrames(render_idle_frames))
+            .add_systems(Render, count_render_idle_frames.in_set(RenderSet::Cleanup));
+    }
+}
+
+#[derive(Resource)]
+pub struct CaptureReadiness {
+    /// Capture starts after this many frames even if the scene is not ready
+    pub timeout_frames: u32,
+    /// Frames the pipeline cache has to stay idle after everything is spawned, new pipelines are
+    /// queued as meshes show up
+    pub settle_frames: u32,
+    tracked_assets: Vec<UntypedHandle>,
+    frames_waited: u32,
+    idle_frames: u32,
+    ready: bool,
+    render_idle_frames: Arc<AtomicUsize>,
+}
+
+impl Default for CaptureReadiness {
+    fn default() -> Self {
+        CaptureReadiness {
+            timeout_frames: 600,
+            settle_frames: 3,
+            tracked_assets: vec![],
+            frames_waited: 0,
+            idle_frames: 0,
+            ready: false,
+            render_idle_frames: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl CaptureReadiness {
+    /// Holds capture back until `handle` is loaded with all its dependencies. Scenes spawned with
+    /// a `Handle<Scene>` are tracked without this.
+    pub fn track<A: Asset>(&mut self, handle: &Handle<A>) {
+        self.tracked_assets.push(handle.clone().untyped());
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Waits for the scene to settle again, e.g. after loading a new one
+    pub fn reset(&mut self) {
+        self.frames_waited = 0;
+        self.idle_frames = 0;
+        self.ready = false;
+    }
+}
+
+/// Render frames in a row without pipelines being compiled or added. The render world runs
+/// behind the main world, so nothing counts as idle before it rendered.
+#[derive(Resource)]
+struct RenderIdleFrames(Arc<AtomicUsize>);
+
+fn count_render_idle_frames(
+    pipeline_cache: Res<PipelineCache>,
+    idle_frames: Res<RenderIdleFrames>,
+    mut pipeline_count: Local<usize>,
+) {
+    let count = pipeline_cache.pipelines().count();
+    if pipeline_cache.waiting_pipelines().count() == 0 && count == *pipeline_count {
+        idle_frames.0.fetch_add(1, Ordering::Relaxed);
+    } else {
+        idle_frames.0.store(0, Ordering::Relaxed);
+    }
+    *pipeline_count = count;
+}
+
+/// Assets that never went through the asset server count as loaded, failed ones are reported
+/// and left to the timeout
+fn is_loaded(asset_server: &AssetServer, handle: &UntypedHandle) -> bool {
+    match asset_server.get_load_states(handle.id()) {
+        None => true,
+        Some((_, _, RecursiveDependencyLoadState::Loaded)) => true,
+        Some((_, _, RecursiveDependencyLoadState::Failed)) => {
+            warn_once!("Asset {:?} failed to load, capture waits for the readiness timeout", handle.path());
+            false
+        }
+        Some(_) => false,
+    }
+}
+
+pub(crate) fn update_capture_readiness(
+    mut readiness: ResMut<CaptureReadiness>,
+    asset_server: Res<AssetServer>,
+    scene_spawner: Option<Res<SceneSpawner>>,
+    scene_query: Query<(&Handle<Scene>, Option<&SceneInstance>)>,
+) {
+    if readiness.ready {
+        return;
+    }
+
+    readiness.frames_waited += 1;
+
+    let assets_loaded = readiness
+        .tracked_assets
+        .iter()
+        .all(|handle| is_loaded(&asset_server, handle));
+
+    let scenes_spawned = scene_query.iter().all(|(handle, instance)| {
+        is_loaded(&asset_server, &handle.clone().untyped())
+            && match (instance, &scene_spawner) {
+                (Some(instance), Some(scene_spawner)) => scene_spawner.instance_is_ready(**instance),
+                _ => false,
+            }
+    });
+
+    // meshes of a freshly spawned scene reach the render world a frame or two later, so both
+    // worlds have to be idle since everything is spawned
+    let render_idle_frames = readiness.render_idle_frames.load(Ordering::Relaxed) as u32;
+    if assets_loaded && scenes_spawned && render_idle_frames > 0 {
+        readiness.idle_frames += 1;
+    } else {
+        readiness.idle_frames = 0;
+    }
+
+    let pipelines_idle = readiness.idle_frames.min(render_idle_frames) >= readiness.settle_frames;
+
+    if pipelines_idle {
+        info!("Scene ready for capture after {} frames", readiness.frames_waited);
+        readiness.ready = true;
+    } else if readiness.frames_waited >= readiness.timeout_frames {
+        warn!(
+            "Scene not ready after {} frames (assets loaded: {}, scenes spawned: {}, pipelines idle: {}), capturing anyway",
+            readiness.frames_waited,
+            assets_loaded,
+            scenes_spawned,
+            pipelines_idle,
+        );
+        readiness.ready = true;
+    }
+}
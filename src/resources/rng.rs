@@ -0,0 +1,109 @@
+//! Seeded RNG
+//!
+//! All randomness of a run comes from the seed in the `OutputConfig`. Every step and every user
+//! of randomness (a randomizer, the class colors, ...) gets its own ChaCha stream derived from
+//! `(seed, step, name)`, so the values drawn for a frame neither depend on earlier frames nor on
+//! the order systems run in, and any frame can be regenerated from the seed alone.
+//!
+//! Steps count updates since the scene became ready for capture, how many frames loading takes
+//! depends on the machine. Every update before that and the first captured one are step 0, or
+//! the step of the `RunOffset` when a run is resumed or sharded. Updates a full `ImageWriter`
+//! holds the simulation for repeat the step, see `Backpressure::SlowSimulation`.
+
+use bevy::{core::FrameCount, prelude::*};
+use rand_chacha::{rand_core::SeedableRng, ChaCha8Rng};
+
+use crate::{
+    resources::{CaptureReadiness, FrameMetadata, OutputConfig, RunOffset},
+    utils::image_writer::ImageWriter,
+};
+
+#[derive(Resource, Clone, Debug, Default)]
+pub struct SegmentationRng {
+    seed: u64,
+    frame: u32,
+    step: u32,
+}
+
+impl SegmentationRng {
+    pub fn new(seed: u64) -> Self {
+        SegmentationRng { seed, frame: 0, step: 0 }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Frame captures of this update are saved with, see `FrameBundle`
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// Updates since the scene became ready for capture
+    pub fn step(&self) -> u32 {
+        self.step
+    }
+
+    /// Stream of `name` in the current step
+    pub fn frame_stream(&self, name: &str) -> ChaCha8Rng {
+        self.stream_at(self.step, name)
+    }
+
+    /// Stream of `name` in `step`, the same in every run with this seed
+    pub fn stream_at(&self, step: u32, name: &str) -> ChaCha8Rng {
+        derive_stream(self.seed, Some(step), name)
+    }
+
+    /// Stream of `name` that does not change between frames, e.g. for choices made once per run
+    pub fn run_stream(&self, name: &str) -> ChaCha8Rng {
+        derive_stream(self.seed, None, name)
+    }
+}
+
+/// ChaCha key from the seed, step and a stable hash of the name, step streams and run streams
+/// never share a key
+fn derive_stream(seed: u64, step: Option<u32>, name: &str) -> ChaCha8Rng {
+    let step = step.map_or(0, |step| (1 << 32) | step as u64);
+
+    let mut key = [0; 32];
+    key[0..8].copy_from_slice(&seed.to_le_bytes());
+    key[8..16].copy_from_slice(&step.to_le_bytes());
+    key[16..24].copy_from_slice(&fnv1a(name.as_bytes()).to_le_bytes());
+    key[24..32].copy_from_slice(b"bevy_seg");
+
+    ChaCha8Rng::from_seed(key)
+}
+
+/// `DefaultHasher` may change between Rust releases, FNV-1a does not
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Follows the seed of the `OutputConfig`, the frame about to be rendered and the step, which is
+/// recorded in the `FrameMetadata`. `FrameCount` is bumped in `Last` and extracted afterwards, so
+/// readbacks of this update carry the next count.
+pub(crate) fn advance_segmentation_rng(
+    output: Res<OutputConfig>,
+    frame_count: Res<FrameCount>,
+    readiness: Res<CaptureReadiness>,
+    offset: Res<RunOffset>,
+    writer: Res<ImageWriter>,
+    mut rng: ResMut<SegmentationRng>,
+    mut metadata: ResMut<FrameMetadata>,
+) {
+    rng.seed = output.seed;
+    rng.frame = frame_count.0.wrapping_add(1);
+    // readiness is decided at the end of an update, the update it turned ready in is step 0
+    if readiness.is_ready() {
+        // `Backpressure::SlowSimulation` holds the step along with the captures
+        if !writer.is_slowing_simulation() {
+            rng.step += 1;
+        }
+    } else {
+        rng.step = offset.step;
+    }
+
+    metadata.insert(rng.frame, "step", rng.step);
+}
@@ -0,0 +1,233 @@
+//! Dataset Splits
+//!
+//! A `SplitPolicy` in the `OutputConfig` puts every frame bundle into the train, val or test
+//! split. The files of a bundle are written into a folder named after its split, and every split
+//! gets a manifest `splits/<split>.jsonl` with one line per capture.
+//!
+//! Captures are assigned by a key hashed onto the ratios, captures with the same key always share
+//! a split. Consecutive frames are near duplicates, so splitting frame by frame would leak test
+//! frames into training, the keys group them instead:
+//!
+//! - `Block(n)` n consecutive captures
+//! - `Scene` the asset paths of the scenes spawned in the frame, a scene only ever lands in one
+//!   split, in any run. Frames without scenes, e.g. of meshes spawned by hand, fall back to
+//!   blocks of the default size.
+//! - `Seed` the seed, the whole run lands in one split
+//! - `Parameter(pointer)` a value of the `FrameMetadata`, e.g. `/visibility/CarBody~1Mesh`
+//!
+//! Only `Block` depends on the seed of the run.
+
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{
+    fs::OpenOptions,
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{CaptureFailed, SegmentationError},
+    resources::{
+        camera_table, CameraOutputTable, CheckpointConfig, ExporterState, FrameBundle, FrameMetadata,
+        OutputConfig, SegmentationRng, Shard,
+    },
+    utils::image_writer::ImageWriter,
+};
+
+/// Key of the manifest sizes in the `ExporterState`
+const EXPORTER_KEY: &str = "splits";
+
+/// Captures per block of the default policy, and of `SplitKey::Scene` in frames without scenes
+const DEFAULT_BLOCK: u32 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Split {
+    Train,
+    Val,
+    Test,
+}
+
+impl Split {
+    pub const ALL: [Split; 3] = [Split::Train, Split::Val, Split::Test];
+
+    /// Folder and manifest name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Split::Train => "train",
+            Split::Val => "val",
+            Split::Test => "test",
+        }
+    }
+}
+
+/// What captures are grouped by, see the module docs
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum SplitKey {
+    Block(u32),
+    Scene,
+    Seed,
+    Parameter(String),
+}
+
+/// Shares of the splits, they do not have to add up to one
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SplitPolicy {
+    pub train: f32,
+    pub val: f32,
+    pub test: f32,
+    pub key: SplitKey,
+}
+
+impl Default for SplitPolicy {
+    fn default() -> Self {
+        SplitPolicy {
+            train: 0.8,
+            val: 0.1,
+            test: 0.1,
+            key: SplitKey::Block(DEFAULT_BLOCK),
+        }
+    }
+}
+
+impl SplitPolicy {
+    /// Split of the capture `index`, `entries` are its `FrameMetadata`
+    pub fn split(&self, seed: u64, index: u32, entries: Option<&Map<String, Value>>) -> Split {
+        let lookup = |pointer: &str| {
+            let entries = Value::Object(entries.cloned().unwrap_or_default());
+            entries.pointer(pointer).cloned().unwrap_or(Value::Null)
+        };
+        let block = |size: u32| SegmentationRng::new(seed).run_stream(&format!("split/block/{}", index / size.max(1)));
+
+        // a stable hash of the key, seeded by the run only for blocks
+        let mut rng = match &self.key {
+            SplitKey::Block(size) => block(*size),
+            SplitKey::Scene => match lookup("/scenes") {
+                scenes if has_scenes(&scenes) => SegmentationRng::new(0).run_stream(&format!("split/scene/{}", scenes)),
+                _ => block(DEFAULT_BLOCK),
+            },
+            SplitKey::Seed => SegmentationRng::new(0).run_stream(&format!("split/seed/{}", seed)),
+            SplitKey::Parameter(pointer) => {
+                SegmentationRng::new(0).run_stream(&format!("split/parameter/{}", lookup(pointer)))
+            }
+        };
+
+        let total = self.train + self.val + self.test;
+        let draw = rng.gen::<f32>() * total;
+        if draw < self.train || total <= 0.0 {
+            Split::Train
+        } else if draw < self.train + self.val {
+            Split::Val
+        } else {
+            Split::Test
+        }
+    }
+}
+
+fn has_scenes(scenes: &Value) -> bool {
+    scenes.as_array().is_some_and(|scenes| !scenes.is_empty())
+}
+
+/// Manifest of `split`, shards of a run each keep their own
+pub fn manifest_path(output: &OutputConfig, config: Option<&CheckpointConfig>, split: Split) -> PathBuf {
+    let shard = config.and_then(|config| config.shard);
+    output
+        .run_dir()
+        .join("splits")
+        .join(format!("{}{}.jsonl", split.name(), Shard::file_suffix(shard)))
+}
+
+/// Cuts the manifests back to their size at the checkpoint, the lines after it are captured
+/// again. Without a checkpoint they start empty.
+pub(crate) fn reset_split_manifests(
+    output: Res<OutputConfig>,
+    config: Option<Res<CheckpointConfig>>,
+    exporters: Res<ExporterState>,
+    mut failures: EventWriter<CaptureFailed>,
+) {
+    if output.split.is_none() {
+        return;
+    }
+
+    let sizes = exporters.0.get(EXPORTER_KEY).and_then(Value::as_object);
+    for split in Split::ALL {
+        let path = manifest_path(&output, config.as_deref(), split);
+        let size = sizes.and_then(|sizes| sizes.get(split.name())).and_then(Value::as_u64).unwrap_or(0);
+        if let Err(error) = truncate(&path, size) {
+            failures.send(CaptureFailed { error });
+        }
+    }
+}
+
+fn truncate(path: &Path, size: u64) -> Result<(), SegmentationError> {
+    match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file.set_len(size).map_err(|e| SegmentationError::io(path, e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(SegmentationError::io(path, e)),
+    }
+}
+
+/// Assigns the split of the bundle the `CameraOutputTable` holds, before it is saved, and queues
+/// its line in the manifest of the split on the `ImageWriter`
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn assign_split(
+    mut frame_bundles: EventReader<FrameBundle>,
+    mut image_table: ResMut<CameraOutputTable>,
+    mut metadata: ResMut<FrameMetadata>,
+    mut exporters: ResMut<ExporterState>,
+    output: Res<OutputConfig>,
+    config: Option<Res<CheckpointConfig>>,
+    mut writer: ResMut<ImageWriter>,
+    mut warned: Local<bool>,
+) {
+    if frame_bundles.read().count() == 0 {
+        return;
+    }
+
+    let (Some(policy), Some(index), Some(frame)) = (&output.split, image_table.capture_index, image_table.frame)
+    else {
+        image_table.split = None;
+        return;
+    };
+
+    let scenes = metadata.get(frame).and_then(|entries| entries.get("scenes"));
+    if policy.key == SplitKey::Scene && !scenes.is_some_and(has_scenes) && !*warned {
+        warn!("Frame {} has no scenes to split by, falling back to blocks of {} captures", frame, DEFAULT_BLOCK);
+        *warned = true;
+    }
+
+    let split = policy.split(output.seed, index, metadata.get(frame));
+    image_table.split = Some(split);
+    metadata.insert(frame, "split", split);
+
+    // the size of the manifest once the lines queued so far are written, kept for the checkpoint
+    let sizes = exporters.0.entry(EXPORTER_KEY).or_insert_with(|| Value::Object(Map::new()));
+    let Some(sizes) = sizes.as_object_mut() else {
+        return;
+    };
+    let offset = sizes.get(split.name()).and_then(Value::as_u64).unwrap_or(0);
+    let line = format!("{}\n", json!({ "index": index, "frame": frame }));
+    let size = offset + line.len() as u64;
+
+    let path = manifest_path(&output, config.as_deref(), split);
+    let name = format!("frame {} {} manifest line", frame, split.name());
+    if writer.write(name, move || write_line(&path, offset, &line)) {
+        sizes.insert(split.name().to_string(), size.into());
+    }
+}
+
+/// Writes `line` at `offset`, where the manifest ends once the lines before it are written, so
+/// retries and lines written out of order leave the same file
+fn write_line(path: &Path, offset: u64, line: &str) -> Result<(), SegmentationError> {
+    camera_table::create_parent_dir(path)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(|e| SegmentationError::io(path, e))?;
+    file.seek(SeekFrom::Start(offset)).map_err(|e| SegmentationError::io(path, e))?;
+    file.write_all(line.as_bytes()).map_err(|e| SegmentationError::io(path, e))
+}
@@ -0,0 +1,272 @@
+//! Encoding
+//!
+//! File formats captures can be saved in. Every modality picks its own `Encoding` in the
+//! `OutputConfig`, e.g. fast JPEG for RGB next to lossless PNG masks and raw NPY depth.
+
+use bevy::{
+    prelude::default,
+    render::{render_resource::TextureFormat, texture::Image},
+};
+use image::{
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType, PngEncoder},
+        webp::WebPEncoder,
+    },
+    DynamicImage, ImageBuffer, Luma,
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs::File, io::BufWriter, path::Path, str::FromStr};
+
+use crate::{
+    components::Modality,
+    error::SegmentationError,
+    materials::decode_depth,
+    utils::hdr::write_exr,
+};
+
+/// Written as in `FromStr` in job files, e.g. `"jpeg:95"`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Encoding {
+    /// PNG with a zlib compression level from 0 (none) to 9 (smallest)
+    Png { compression: u8 },
+    /// JPEG with a quality from 1 to 100, lossy so only meant for RGB
+    Jpeg { quality: u8 },
+    /// Lossless WebP
+    WebP,
+    /// OpenEXR, for float images
+    Exr,
+    /// NumPy array of the raw values, e.g. metric depth as `float32`
+    Npy,
+    /// NPY compressed with zstd at `level`
+    Zstd { level: i32 },
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Png { compression: 1 }
+    }
+}
+
+impl Encoding {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Png { .. } => "png",
+            Encoding::Jpeg { .. } => "jpg",
+            Encoding::WebP => "webp",
+            Encoding::Exr => "exr",
+            Encoding::Npy => "npy",
+            Encoding::Zstd { .. } => "npy.zst",
+        }
+    }
+}
+
+/// Parses `png`, `png:<compression>`, `jpeg`, `jpeg:<quality>`, `webp`, `exr`, `npy`, `zstd` and
+/// `zstd:<level>`
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, parameter) = match s.split_once(':') {
+            Some((name, parameter)) => (name, Some(parameter)),
+            None => (s, None),
+        };
+        let parse = |default| match parameter {
+            Some(parameter) => parameter
+                .parse::<i32>()
+                .map_err(|e| format!("invalid parameter in encoding '{}': {}", s, e)),
+            None => Ok(default),
+        };
+
+        match name.to_ascii_lowercase().as_str() {
+            "png" => Ok(Encoding::Png { compression: parse(1)?.clamp(0, 9) as u8 }),
+            "jpeg" | "jpg" => Ok(Encoding::Jpeg { quality: parse(90)?.clamp(1, 100) as u8 }),
+            "webp" if parameter.is_none() => Ok(Encoding::WebP),
+            "exr" if parameter.is_none() => Ok(Encoding::Exr),
+            "npy" if parameter.is_none() => Ok(Encoding::Npy),
+            "zstd" => Ok(Encoding::Zstd { level: parse(3)? }),
+            _ => Err(format!("unknown encoding '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Png { compression } => write!(f, "png:{}", compression),
+            Encoding::Jpeg { quality } => write!(f, "jpeg:{}", quality),
+            Encoding::WebP => write!(f, "webp"),
+            Encoding::Exr => write!(f, "exr"),
+            Encoding::Npy => write!(f, "npy"),
+            Encoding::Zstd { level } => write!(f, "zstd:{}", level),
+        }
+    }
+}
+
+impl TryFrom<String> for Encoding {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Encoding> for String {
+    fn from(encoding: Encoding) -> Self {
+        encoding.to_string()
+    }
+}
+
+/// Raw values of an image as an NPY type descriptor, array shape and little endian data
+struct RawArray {
+    descr: &'static str,
+    shape: Vec<usize>,
+    data: Vec<u8>,
+}
+
+impl RawArray {
+    fn from_image(image: &Image) -> Option<Self> {
+        let (height, width) = (image.height() as usize, image.width() as usize);
+
+        let (descr, channels) = match image.texture_descriptor.format {
+            TextureFormat::R8Unorm | TextureFormat::R8Uint => ("|u1", 1),
+            TextureFormat::Rg8Unorm => ("|u1", 2),
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => ("|u1", 4),
+            TextureFormat::R16Unorm | TextureFormat::R16Uint | TextureFormat::Depth16Unorm => ("<u2", 1),
+            TextureFormat::R16Float => ("<f2", 1),
+            TextureFormat::Rgba16Float => ("<f2", 4),
+            TextureFormat::R32Float | TextureFormat::Depth32Float => ("<f4", 1),
+            TextureFormat::R32Uint => ("<u4", 1),
+            TextureFormat::Rgba32Float => ("<f4", 4),
+            TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => {
+                let mut data = image.data.clone();
+                for bgra in data.chunks_exact_mut(4) {
+                    bgra.swap(0, 2);
+                }
+                return Some(RawArray { descr: "|u1", shape: vec![height, width, 4], data });
+            }
+            _ => return None,
+        };
+
+        let shape = match channels {
+            1 => vec![height, width],
+            _ => vec![height, width, channels],
+        };
+
+        Some(RawArray { descr, shape, data: image.data.clone() })
+    }
+
+    /// NPY version 1.0 file
+    fn to_npy(&self) -> Vec<u8> {
+        let shape = match self.shape.as_slice() {
+            [n] => format!("({},)", n),
+            dims => format!("({})", dims.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")),
+        };
+        let mut header = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+            self.descr, shape,
+        );
+        // magic, version and header length take 10 bytes, the data starts 64 byte aligned
+        let padding = 63 - (10 + header.len()) % 64;
+        header.extend(std::iter::repeat(' ').take(padding));
+        header.push('\n');
+
+        let mut npy = b"\x93NUMPY\x01\x00".to_vec();
+        npy.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        npy.extend_from_slice(header.as_bytes());
+        npy.extend_from_slice(&self.data);
+        npy
+    }
+
+    fn save(&self, path: &Path, encoding: Encoding) -> Result<(), SegmentationError> {
+        let bytes = match encoding {
+            Encoding::Zstd { level } => zstd::encode_all(&self.to_npy()[..], level)
+                .map_err(|e| SegmentationError::encode(path, e))?,
+            _ => self.to_npy(),
+        };
+        std::fs::write(path, bytes).map_err(|e| SegmentationError::io(path, e))
+    }
+}
+
+/// Saves a camera output to `path` in `encoding`. Depth images hold packed depth, see
+/// `DepthMaterial`, every encoding gets the decoded metric depth as a single float channel, so
+/// only EXR, NPY and zstd can hold it.
+pub fn save_image(
+    image: &Image,
+    modality: Modality,
+    encoding: Encoding,
+    path: &Path,
+) -> Result<(), SegmentationError> {
+    let decoded;
+    let image = match modality {
+        Modality::Depth => {
+            decoded = decoded_depth(image);
+            &decoded
+        }
+        _ => image,
+    };
+
+    let format = image.texture_descriptor.format;
+    let unsupported = || SegmentationError::unsupported(path, format, encoding);
+
+    match encoding {
+        Encoding::Exr => write_exr(path, image),
+        Encoding::Npy | Encoding::Zstd { .. } => RawArray::from_image(image)
+            .ok_or_else(unsupported)?
+            .save(path, encoding),
+        Encoding::Png { .. } | Encoding::Jpeg { .. } | Encoding::WebP => {
+            let dynamic = image.clone().try_into_dynamic().map_err(|_| unsupported())?;
+            save_dynamic(&dynamic, encoding, path)
+        }
+    }
+}
+
+/// `R32Float` image of the metric depth in a packed depth image
+fn decoded_depth(image: &Image) -> Image {
+    let data = decode_depth(image).into_iter().flat_map(f32::to_le_bytes).collect();
+    let mut decoded = Image {
+        data,
+        ..default()
+    };
+    decoded.texture_descriptor.size = image.texture_descriptor.size;
+    decoded.texture_descriptor.format = TextureFormat::R32Float;
+    decoded
+}
+
+/// Saves a KITTI disparity map to `path`, only lossless encodings keep it intact
+pub fn save_disparity(
+    disparity: ImageBuffer<Luma<u16>, Vec<u16>>,
+    encoding: Encoding,
+    path: &Path,
+) -> Result<(), SegmentationError> {
+    match encoding {
+        Encoding::Npy | Encoding::Zstd { .. } => RawArray {
+            descr: "<u2",
+            shape: vec![disparity.height() as usize, disparity.width() as usize],
+            data: disparity.iter().flat_map(|d| d.to_le_bytes()).collect(),
+        }
+        .save(path, encoding),
+        Encoding::Png { .. } => save_dynamic(&DynamicImage::ImageLuma16(disparity), encoding, path),
+        _ => Err(SegmentationError::unsupported(path, TextureFormat::R16Uint, encoding)),
+    }
+}
+
+fn save_dynamic(image: &DynamicImage, encoding: Encoding, path: &Path) -> Result<(), SegmentationError> {
+    let file = BufWriter::new(File::create(path).map_err(|e| SegmentationError::io(path, e))?);
+
+    match encoding {
+        Encoding::Png { compression } => image.write_with_encoder(PngEncoder::new_with_quality(
+            file,
+            CompressionType::Level(compression.min(9)),
+            FilterType::Adaptive,
+        )),
+        // no alpha in JPEG
+        Encoding::Jpeg { quality } => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(file, quality.clamp(1, 100))),
+        Encoding::WebP => DynamicImage::ImageRgba8(image.to_rgba8())
+            .write_with_encoder(WebPEncoder::new_lossless(file)),
+        _ => return Err(SegmentationError::encode(path, format!("{:?} is not an image encoding", encoding))),
+    }
+    .map_err(|e| SegmentationError::encode(path, e))
+}
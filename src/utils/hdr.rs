@@ -0,0 +1,176 @@
+//! HDR
+//!
+//! Float captures hold linear radiance. They are written as single part, uncompressed scanline
+//! OpenEXR files, which is all the writer here supports, and can be tonemapped to 8 bit previews.
+//! Single channel float images, e.g. decoded depth, are written as a `Y` channel.
+
+use bevy::render::{render_resource::TextureFormat, texture::Image};
+use image::RgbaImage;
+use std::path::Path;
+
+use crate::{error::SegmentationError, utils::encoding::Encoding};
+
+/// Whether `format` holds linear float color and is saved as EXR
+pub fn is_hdr_format(format: TextureFormat) -> bool {
+    matches!(format, TextureFormat::Rgba16Float | TextureFormat::Rgba32Float)
+}
+
+/// Bytes of one channel of a RGBA float format, `None` for other formats
+fn channel_size(format: TextureFormat) -> Option<usize> {
+    match format {
+        TextureFormat::Rgba16Float => Some(2),
+        TextureFormat::Rgba32Float => Some(4),
+        _ => None,
+    }
+}
+
+/// Writes a `Rgba16Float`, `Rgba32Float`, `R16Float` or `R32Float` image to `path` as OpenEXR,
+/// keeping the precision
+pub fn write_exr(path: &Path, image: &Image) -> Result<(), SegmentationError> {
+    let format = image.texture_descriptor.format;
+    // Channels are stored in alphabetical order, each as a run of samples per scanline
+    let (channels, channel_size): (&[(&str, usize)], usize) = match format {
+        TextureFormat::R16Float => (&[("Y", 0)], 2),
+        TextureFormat::R32Float => (&[("Y", 0)], 4),
+        _ => match channel_size(format) {
+            Some(channel_size) => (&[("A", 3), ("B", 2), ("G", 1), ("R", 0)], channel_size),
+            None => return Err(SegmentationError::unsupported(path, format, Encoding::Exr)),
+        },
+    };
+    let pixel_size = channels.len() * channel_size;
+
+    let width = image.width() as usize;
+    let height = image.height() as usize;
+    if image.data.len() != width * height * pixel_size {
+        return Err(SegmentationError::encode(path, "image size does not match its data"));
+    }
+
+    let pixel_type: i32 = if channel_size == 2 { 1 } else { 2 };
+
+    let mut channel_list = Vec::new();
+    for (name, _) in channels {
+        channel_list.extend_from_slice(name.as_bytes());
+        channel_list.push(0);
+        channel_list.extend_from_slice(&pixel_type.to_le_bytes());
+        // pLinear and reserved bytes
+        channel_list.extend_from_slice(&[0; 4]);
+        // x and y sampling
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channel_list.push(0);
+
+    let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+
+    let mut file = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+        for text in [name, kind] {
+            file.extend_from_slice(text.as_bytes());
+            file.push(0);
+        }
+        file.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        file.extend_from_slice(value);
+    };
+    attribute("channels", "chlist", &channel_list);
+    attribute("compression", "compression", &[0]);
+    attribute("dataWindow", "box2i", &window);
+    attribute("displayWindow", "box2i", &window);
+    attribute("lineOrder", "lineOrder", &[0]);
+    attribute("pixelAspectRatio", "float", &1f32.to_le_bytes());
+    attribute("screenWindowCenter", "v2f", &[0; 8]);
+    attribute("screenWindowWidth", "float", &1f32.to_le_bytes());
+    file.push(0);
+
+    // Offset table, one uncompressed scanline per chunk
+    let line_size = width * pixel_size;
+    let first_line = file.len() + height * 8;
+    for y in 0..height {
+        let offset = (first_line + y * (line_size + 8)) as u64;
+        file.extend_from_slice(&offset.to_le_bytes());
+    }
+
+    for (y, row) in image.data.chunks_exact(line_size).enumerate() {
+        file.extend_from_slice(&(y as i32).to_le_bytes());
+        file.extend_from_slice(&(line_size as i32).to_le_bytes());
+        for (_, component) in channels {
+            for pixel in row.chunks_exact(pixel_size) {
+                let start = component * channel_size;
+                file.extend_from_slice(&pixel[start..start + channel_size]);
+            }
+        }
+    }
+
+    std::fs::write(path, file).map_err(|e| SegmentationError::io(path, e))
+}
+
+/// Linear RGBA of a float image, `None` for other formats
+pub fn decode_linear(image: &Image) -> Option<Vec<[f32; 4]>> {
+    let channel_size = channel_size(image.texture_descriptor.format)?;
+
+    let channel = |bytes: &[u8]| match channel_size {
+        2 => f16_to_f32(u16::from_le_bytes([bytes[0], bytes[1]])),
+        _ => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    };
+
+    Some(
+        image
+            .data
+            .chunks_exact(4 * channel_size)
+            .map(|pixel| {
+                let mut rgba = [0.0; 4];
+                for (value, bytes) in rgba.iter_mut().zip(pixel.chunks_exact(channel_size)) {
+                    *value = channel(bytes);
+                }
+                rgba
+            })
+            .collect(),
+    )
+}
+
+/// 8 bit sRGB preview of a float image, tonemapped with Reinhard at the given exposure in stops
+pub fn tonemapped_preview(image: &Image, exposure: f32) -> Option<RgbaImage> {
+    let scale = exposure.exp2();
+    let data = decode_linear(image)?
+        .into_iter()
+        .flat_map(|[r, g, b, a]| {
+            let [r, g, b] = [r, g, b].map(|c| {
+                let c = (c * scale).max(0.0);
+                linear_to_srgb(c / (1.0 + c))
+            });
+            [r, g, b, a.clamp(0.0, 1.0)].map(|c| (c * 255.0).round() as u8)
+        })
+        .collect();
+
+    RgbaImage::from_raw(image.width(), image.height(), data)
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// IEEE 754 half to single precision
+pub fn f16_to_f32(bits: u16) -> f32 {
+    let sign = ((bits >> 15) as u32) << 31;
+    let exponent = ((bits >> 10) & 0x1f) as u32;
+    let mantissa = (bits & 0x3ff) as u32;
+
+    let value = match exponent {
+        0 if mantissa == 0 => sign,
+        // subnormal, exact as a float
+        0 => {
+            let magnitude = mantissa as f32 * (-24f32).exp2();
+            return if sign != 0 { -magnitude } else { magnitude };
+        }
+        0x1f => sign | 0x7f80_0000 | (mantissa << 13),
+        _ => sign | ((exponent + 112) << 23) | (mantissa << 13),
+    };
+
+    f32::from_bits(value)
+}
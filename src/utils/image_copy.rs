@@ -1,33 +1,115 @@
 use bevy::{
+    core::FrameCount,
     prelude::*,
     render::{
         render_asset::RenderAssets,
         render_graph::{self, NodeRunError, RenderGraph, RenderGraphContext, RenderLabel},
         render_resource::{
-            Buffer, BufferDescriptor, BufferUsages, CommandEncoderDescriptor, Extent3d,
-            ImageCopyBuffer, ImageDataLayout, Maintain, MapMode, 
+            Buffer, BufferDescriptor, BufferUsages, Extent3d,
+            ImageCopyBuffer, ImageCopyTexture, ImageDataLayout, Maintain, MapMode, TextureAspect,
+            TextureFormat,
         },
-        renderer::{RenderContext, RenderDevice, RenderQueue},
+        renderer::{RenderContext, RenderDevice},
+        texture::GpuImage,
         Extract, Render, RenderApp, RenderSet,
     },
 };
 use crossbeam_channel::Sender;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering},
     Arc,
 };
 
+use crate::error::{RenderErrorSender, SegmentationError};
+
 // To communicate between the main world and the render world we need a channel.
 // Since the main world and render world run in parallel, there will always be a frame of latency
 // between the data sent from the render world and the data received in the main world
 //
-// frame n => render world sends data through the channel at the end of the frame
-// frame n + 1 => main world receives the data
+// frame n => render world copies the image to a staging buffer and maps it
+// frame n + k => render world sends the data once the buffer is mapped (usually k = 1)
+// frame n + k + 1 => main world receives the data
+//
+// Every camera has its own channel, and each readback is tagged with the camera and the frame it
+// was rendered in, so the main world can put together images of the same frame
 //
-// Receiver and Sender are kept in resources because there is single camera and single target
-// That's why there is single images role, if you want to differentiate images
-// from different cameras, you should keep Receiver in ImageCopier and Sender in ImageToSave
-// or send some id with data
+// Readbacks never block the render world. Each `ImageCopier` owns a ring of staging buffers,
+// a frame is copied into a free one, mapped after the copy was submitted and sent on a later frame
+// once wgpu reports the mapping done. When every buffer is still in flight the frame is skipped.
+
+/// Staging buffers per `ImageCopier`, i.e. the number of readbacks that can be in flight
+pub const READBACK_RING_SIZE: usize = 3;
+
+/// How a texture is laid out in a staging buffer
+///
+/// `copy_texture_to_buffer` can copy image only by rows aligned to
+/// `wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`, that's why the image in the buffer can be a little bit
+/// wider than the texture. Combined depth stencil formats only read back their depth aspect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadbackLayout {
+    pub width: u32,
+    pub height: u32,
+    pub format: TextureFormat,
+    pub aspect: TextureAspect,
+    /// Bytes of one block (a texel for uncompressed formats) of the copied aspect
+    pub block_size: usize,
+    /// Bytes of one row in the image
+    pub bytes_per_row: usize,
+    /// Bytes of one row in the staging buffer
+    pub padded_bytes_per_row: usize,
+    /// Rows of blocks
+    pub rows: usize,
+}
+
+impl ReadbackLayout {
+    /// `None` if `format` can not be copied to a buffer, e.g. `Depth24Plus`
+    pub fn new(width: u32, height: u32, format: TextureFormat) -> Option<Self> {
+        let aspect = if format.is_combined_depth_stencil_format() {
+            TextureAspect::DepthOnly
+        } else {
+            TextureAspect::All
+        };
+        let block_size = format.block_copy_size(Some(aspect))? as usize;
+        let (block_width, block_height) = format.block_dimensions();
+
+        let bytes_per_row = width.div_ceil(block_width) as usize * block_size;
+
+        Some(ReadbackLayout {
+            width,
+            height,
+            format,
+            aspect,
+            block_size,
+            bytes_per_row,
+            padded_bytes_per_row: RenderDevice::align_copy_bytes_per_row(bytes_per_row),
+            rows: height.div_ceil(block_height) as usize,
+        })
+    }
+
+    /// Size of a staging buffer holding the whole image
+    pub fn buffer_size(&self) -> u64 {
+        self.padded_bytes_per_row as u64 * self.rows as u64
+    }
+
+    /// Size of the image without row padding
+    pub fn image_size(&self) -> usize {
+        self.bytes_per_row * self.rows
+    }
+
+    /// Strips the row padding off a staging buffer's content
+    pub fn unpad(&self, padded: &[u8]) -> Vec<u8> {
+        if self.bytes_per_row == self.padded_bytes_per_row {
+            return padded[..self.image_size().min(padded.len())].to_vec();
+        }
+
+        padded
+            .chunks(self.padded_bytes_per_row)
+            .take(self.rows)
+            .flat_map(|row| &row[..self.bytes_per_row.min(row.len())])
+            .copied()
+            .collect()
+    }
+}
 
 /// Plugin for Render world part of work
 pub struct ImageCopyPlugin;
@@ -50,53 +132,148 @@ impl Plugin for ImageCopyPlugin {
     }
 }
 
-/// `ImageCopier` aggregator in `RenderWorld`
+/// `ImageCopier` aggregator in `RenderWorld`, with whether each copies the extracted frame
 #[derive(Clone, Default, Resource, Deref, DerefMut)]
-struct ImageCopiers(pub Vec<ImageCopier>);
+struct ImageCopiers(pub Vec<(ImageCopier, bool)>);
+
+/// Image data read back from the GPU
+pub struct ImageReadback {
+    /// Main world frame the image was rendered in
+    pub frame: u32,
+    /// Index of the camera in the `CameraOutputTable`
+    pub camera_id: usize,
+    /// Rows without padding, see `ReadbackLayout`
+    pub data: Vec<u8>,
+}
+
+/// Where a staging buffer is in its readback
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StagingState {
+    Free,
+    /// Copy submitted, not mapped yet
+    Copying,
+    /// `map_async` called, waiting for the callback
+    Mapping,
+    /// Readable on the CPU
+    Mapped,
+    MapFailed,
+}
+
+impl StagingState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => StagingState::Free,
+            1 => StagingState::Copying,
+            2 => StagingState::Mapping,
+            3 => StagingState::Mapped,
+            _ => StagingState::MapFailed,
+        }
+    }
+}
+
+/// One buffer of an `ImageCopier`'s readback ring
+struct StagingBuffer {
+    buffer: Buffer,
+    state: Arc<AtomicU8>,
+    /// Frame the buffer holds once copied
+    frame: AtomicU32,
+}
+
+impl StagingBuffer {
+    fn state(&self) -> StagingState {
+        StagingState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn set_state(&self, state: StagingState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+}
 
 /// Used by `ImageCopyDriver` for copying from render target to buffer
 #[derive(Clone, Component)]
 pub struct ImageCopier {
-    buffer: Buffer,
+    staging: Arc<Vec<StagingBuffer>>,
+    layout: ReadbackLayout,
+    camera_id: usize,
     enabled: Arc<AtomicBool>,
-    sender: Sender<Vec<u8>>,
+    sender: Sender<ImageReadback>,
     src_image: Handle<Image>,
 }
 
 impl ImageCopier {
     pub fn new(
-        sender: Sender<Vec<u8>>,
+        camera_id: usize,
+        sender: Sender<ImageReadback>,
         src_image: Handle<Image>,
-        size: Extent3d,
+        layout: ReadbackLayout,
         render_device: &RenderDevice,
     ) -> ImageCopier {
-        let padded_bytes_per_row =
-            RenderDevice::align_copy_bytes_per_row((size.width) as usize) * 4;
-
-        let cpu_buffer = render_device.create_buffer(&BufferDescriptor {
-            label: None,
-            size: padded_bytes_per_row as u64 * size.height as u64,
-            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        let staging = (0..READBACK_RING_SIZE)
+            .map(|_| StagingBuffer {
+                buffer: render_device.create_buffer(&BufferDescriptor {
+                    label: None,
+                    size: layout.buffer_size(),
+                    usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+                state: Arc::new(AtomicU8::new(StagingState::Free as u8)),
+                frame: AtomicU32::new(0),
+            })
+            .collect();
 
         ImageCopier {
-            buffer: cpu_buffer,
-            sender: sender,
+            staging: Arc::new(staging),
+            layout,
+            camera_id,
+            sender,
             src_image,
-            enabled: Arc::new(AtomicBool::new(true)),
+            // frames are only copied once the `CapturePolicy` asks for them
+            enabled: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    pub fn layout(&self) -> &ReadbackLayout {
+        &self.layout
+    }
+
     pub fn enabled(&self) -> bool {
         self.enabled.load(Ordering::Relaxed)
     }
+
+    /// Whether the frame of this update is copied, read when it is extracted
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Claims a free staging buffer for `frame`
+    fn claim_staging_buffer(&self, frame: u32) -> Option<&Buffer> {
+        self.staging.iter().find_map(|staging| {
+            staging.state
+                .compare_exchange(
+                    StagingState::Free as u8,
+                    StagingState::Copying as u8,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .ok()
+                .map(|_| {
+                    staging.frame.store(frame, Ordering::Release);
+                    &staging.buffer
+                })
+        })
+    }
 }
 
-/// Extracting `ImageCopier`s into render world, because `ImageCopyDriver` accesses them
+/// Extracting `ImageCopier`s into render world, because `ImageCopyDriver` accesses them.
+/// With pipelined rendering the previous frame may still be rendering while the main world
+/// decides about the next one, so the enabled flag is taken along with the frame.
 fn image_copy_extract(mut commands: Commands, image_copiers: Extract<Query<&ImageCopier>>) {
     commands.insert_resource(ImageCopiers(
-        image_copiers.iter().cloned().collect::<Vec<ImageCopier>>(),
+        image_copiers
+            .iter()
+            .map(|image_copier| (image_copier.clone(), image_copier.enabled()))
+            .collect(),
     ));
 }
 
@@ -116,128 +293,131 @@ impl render_graph::Node for ImageCopyDriver {
         render_context: &mut RenderContext,
         world: &World,
     ) -> Result<(), NodeRunError> {
-        let image_copiers = world.get_resource::<ImageCopiers>().unwrap();
-        let gpu_images = world
-            .get_resource::<RenderAssets<bevy::render::texture::GpuImage>>()
-            .unwrap();
+        // Nothing extracted yet
+        let (Some(image_copiers), Some(gpu_images)) = (
+            world.get_resource::<ImageCopiers>(),
+            world.get_resource::<RenderAssets<GpuImage>>(),
+        ) else {
+            return Ok(());
+        };
+        let errors = world.get_resource::<RenderErrorSender>();
+        let report = |error: SegmentationError| {
+            if let Some(errors) = errors {
+                let _ = errors.0.send(error);
+            }
+        };
+        let frame = world.resource::<FrameCount>().0;
 
-        for image_copier in image_copiers.iter() {
-            if !image_copier.enabled() {
+        for (image_copier, enabled) in image_copiers.iter() {
+            if !enabled {
                 continue;
             }
 
-            let src_image = gpu_images.get(&image_copier.src_image).unwrap();
-
-            let mut encoder = render_context
-                .render_device()
-                .create_command_encoder(&CommandEncoderDescriptor::default());
+            let Some(src_image) = gpu_images.get(&image_copier.src_image) else {
+                report(SegmentationError::MissingGpuImage { camera_id: image_copier.camera_id });
+                continue;
+            };
 
-            let block_dimensions = src_image.texture_format.block_dimensions();
-            let block_size = src_image.texture_format.block_copy_size(None).unwrap();
+            let layout = image_copier.layout;
+            if src_image.size != UVec2::new(layout.width, layout.height)
+                || src_image.texture_format != layout.format
+            {
+                // the staging buffers were sized for the image the copier was created with
+                report(SegmentationError::SizeMismatch {
+                    camera: image_copier.camera_id.to_string(),
+                    expected: layout.image_size(),
+                    actual: ReadbackLayout::new(src_image.size.x, src_image.size.y, src_image.texture_format)
+                        .map_or(0, |layout| layout.image_size()),
+                });
+                continue;
+            }
 
-            // Calculating correct size of image row because
-            // copy_texture_to_buffer can copy image only by rows aligned wgpu::COPY_BYTES_PER_ROW_ALIGNMENT
-            // That's why image in buffer can be little bit wider
-            // This should be taken into account at copy from buffer stage
-            let padded_bytes_per_row = RenderDevice::align_copy_bytes_per_row(
-                (src_image.size.x as usize / block_dimensions.0 as usize) * block_size as usize,
-            );
+            let Some(buffer) = image_copier.claim_staging_buffer(frame) else {
+                warn!("All staging buffers of camera {} in flight, skipping frame {}", image_copier.camera_id, frame);
+                continue;
+            };
 
             let texture_extent = Extent3d {
-                width: src_image.size.x,
-                height: src_image.size.y,
+                width: layout.width,
+                height: layout.height,
                 depth_or_array_layers: 1,
             };
 
-            encoder.copy_texture_to_buffer(
-                src_image.texture.as_image_copy(),
+            // recorded into the graph's encoder, so the copy is submitted after the cameras
+            // rendered this frame instead of before them
+            render_context.command_encoder().copy_texture_to_buffer(
+                ImageCopyTexture {
+                    aspect: layout.aspect,
+                    ..src_image.texture.as_image_copy()
+                },
                 ImageCopyBuffer {
-                    buffer: &image_copier.buffer,
+                    buffer,
                     layout: ImageDataLayout {
                         offset: 0,
-                        bytes_per_row: Some(
-                            std::num::NonZeroU32::new(padded_bytes_per_row as u32)
-                                .unwrap()
-                                .into(),
-                        ),
+                        bytes_per_row: Some(layout.padded_bytes_per_row as u32),
                         rows_per_image: None,
                     },
                 },
                 texture_extent,
             );
-
-            let render_queue = world.get_resource::<RenderQueue>().unwrap();
-            render_queue.submit(std::iter::once(encoder.finish()));
         }
 
         Ok(())
     }
 }
 
-/// runs in render world after Render stage to send image from buffer via channel (receiver is in main world)
+/// runs in render world after Render stage to send mapped buffers via channel (receiver is in main world)
 fn receive_image_from_buffer(
     image_copiers: Res<ImageCopiers>,
     render_device: Res<RenderDevice>,
+    errors: Option<Res<RenderErrorSender>>,
 ) {
-    for image_copier in image_copiers.0.iter() {
-        if !image_copier.enabled() {
-            continue;
+    for (image_copier, _) in image_copiers.0.iter() {
+        for staging in image_copier.staging.iter() {
+            match staging.state() {
+                StagingState::Mapped => {
+                    let frame = staging.frame.load(Ordering::Acquire);
+
+                    // The main world gets the image without row padding
+                    let data = image_copier.layout.unpad(&staging.buffer.slice(..).get_mapped_range());
+
+                    // This could fail on app exit, if Main world clears resources (including receiver) while Render world still renders
+                    let _ = image_copier.sender.send(ImageReadback {
+                        frame,
+                        camera_id: image_copier.camera_id,
+                        data,
+                    });
+
+                    // The `BufferView` above is dropped, unmap so the buffer can be copied to again
+                    staging.buffer.unmap();
+                    staging.set_state(StagingState::Free);
+                }
+                StagingState::MapFailed => {
+                    if let Some(errors) = &errors {
+                        let _ = errors.0.send(SegmentationError::MapFailed { camera_id: image_copier.camera_id });
+                    }
+                    staging.set_state(StagingState::Free);
+                }
+                StagingState::Copying => {
+                    // WebGPU, for safety reasons, only allows either the GPU or CPU to access a
+                    // buffer's contents at a time. `map_async` flips ownership over to the CPU,
+                    // the callback fires from a later `poll` once the copy is done.
+                    staging.set_state(StagingState::Mapping);
+                    let state = staging.state.clone();
+                    staging.buffer.slice(..).map_async(MapMode::Read, move |result| {
+                        let mapped = match result {
+                            Ok(()) => StagingState::Mapped,
+                            Err(_) => StagingState::MapFailed,
+                        };
+                        state.store(mapped as u8, Ordering::Release);
+                    });
+                }
+                StagingState::Free | StagingState::Mapping => {}
+            }
         }
-
-        // Finally time to get our data back from the gpu.
-        // First we get a buffer slice which represents a chunk of the buffer (which we
-        // can't access yet).
-        // We want the whole thing so use unbounded range.
-        let buffer_slice = image_copier.buffer.slice(..);
-
-        // Now things get complicated. WebGPU, for safety reasons, only allows either the GPU
-        // or CPU to access a buffer's contents at a time. We need to "map" the buffer which means
-        // flipping ownership of the buffer over to the CPU and making access legal. We do this
-        // with `BufferSlice::map_async`.
-        //
-        // The problem is that map_async is not an async function so we can't await it. What
-        // we need to do instead is pass in a closure that will be executed when the slice is
-        // either mapped or the mapping has failed.
-        //
-        // The problem with this is that we don't have a reliable way to wait in the main
-        // code for the buffer to be mapped and even worse, calling get_mapped_range or
-        // get_mapped_range_mut prematurely will cause a panic, not return an error.
-        //
-        // Using channels solves this as awaiting the receiving of a message from
-        // the passed closure will force the outside code to wait. It also doesn't hurt
-        // if the closure finishes before the outside code catches up as the message is
-        // buffered and receiving will just pick that up.
-        //
-        // It may also be worth noting that although on native, the usage of asynchronous
-        // channels is wholly unnecessary, for the sake of portability to Wasm
-        // we'll use async channels that work on both native and Wasm.
-
-        let (s, r) = crossbeam_channel::bounded(1);
-
-        // Maps the buffer so it can be read on the cpu
-        buffer_slice.map_async(MapMode::Read, move |r| match r {
-            // This will execute once the gpu is ready, so after the call to poll()
-            Ok(r) => s.send(r).expect("Failed to send map update"),
-            Err(err) => panic!("Failed to map buffer {err}"),
-        });
-
-        // In order for the mapping to be completed, one of three things must happen.
-        // One of those can be calling `Device::poll`. This isn't necessary on the web as devices
-        // are polled automatically but natively, we need to make sure this happens manually.
-        // `Maintain::Wait` will cause the thread to wait on native but not on WebGpu.
-
-        // This blocks until the gpu is done executing everything
-        render_device.poll(Maintain::wait()).panic_on_timeout();
-
-        // This blocks until the buffer is mapped
-        r.recv().expect("Failed to receive the map_async message");
-
-        // This could fail on app exit, if Main world clears resources (including receiver) while Render world still renders
-        let _ = image_copier.sender.send(buffer_slice.get_mapped_range().to_vec());
-
-        // We need to make sure all `BufferView`'s are dropped before we do what we're about
-        // to do.
-        // Unmap so that we can copy to the staging buffer in the next iteration.
-        image_copier.buffer.unmap();
     }
-}
\ No newline at end of file
+
+    // Drives the map callbacks without waiting on the gpu. This isn't necessary on the web as
+    // devices are polled automatically but natively, we need to make sure this happens manually.
+    render_device.poll(Maintain::Poll);
+}
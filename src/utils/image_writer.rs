@@ -0,0 +1,199 @@
+//! Image Writer
+//!
+//! Encodes and writes captured images on the `AsyncComputeTaskPool`, so the main world only pays
+//! for copying the image data. The number of writes in flight is bounded, `Backpressure` decides
+//! what happens when capture outpaces the disk. Failed writes are retried `retries` times and
+//! then reported as `CaptureFailed`. Pending writes are flushed when the app exits.
+
+use bevy::{
+    app::AppExit,
+    prelude::*,
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+};
+use std::collections::VecDeque;
+
+use crate::error::{CaptureFailed, FailurePolicy, SegmentationError};
+
+/// Polls the `ImageWriter` and flushes it on exit
+pub struct ImageWriterPlugin;
+
+impl Plugin for ImageWriterPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<ImageWriter>()
+            .add_systems(
+                Last,
+                (
+                    poll_image_writer,
+                    flush_image_writer.run_if(on_event::<AppExit>()),
+                ).chain(),
+            );
+    }
+}
+
+/// What to do with a new write while the queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait on the main thread for the oldest write to finish
+    #[default]
+    Block,
+    /// Skip the new write
+    Drop,
+    /// Queue it anyway, then hold virtual time, scheduled captures and the RNG step until the
+    /// queue drained
+    SlowSimulation,
+}
+
+type WriteTask = Task<Result<(), SegmentationError>>;
+
+#[derive(Resource)]
+pub struct ImageWriter {
+    /// Writes in flight before `backpressure` kicks in
+    pub capacity: usize,
+    pub backpressure: Backpressure,
+    /// Extra attempts of a failed write, set from the `FailurePolicy`
+    pub retries: u32,
+    tasks: VecDeque<(String, WriteTask)>,
+    failures: Vec<SegmentationError>,
+    paused_simulation: bool,
+}
+
+impl Default for ImageWriter {
+    fn default() -> Self {
+        ImageWriter {
+            capacity: 32,
+            backpressure: Backpressure::default(),
+            retries: FailurePolicy::default().retries(),
+            tasks: VecDeque::new(),
+            failures: Vec::new(),
+            paused_simulation: false,
+        }
+    }
+}
+
+impl ImageWriter {
+    /// Runs `job` (encoding and writing `name`) in the background, again if it fails. Returns
+    /// whether it was queued, `Backpressure::Drop` skips it when the writer is full.
+    pub fn write<F>(&mut self, name: String, job: F) -> bool
+    where
+        F: Fn() -> Result<(), SegmentationError> + Send + 'static,
+    {
+        if self.is_full() {
+            match self.backpressure {
+                Backpressure::Block => {
+                    while self.is_full() {
+                        self.finish_oldest();
+                    }
+                }
+                Backpressure::Drop => {
+                    warn!("Image writer full, dropping {}", name);
+                    return false;
+                }
+                Backpressure::SlowSimulation => {}
+            }
+        }
+
+        let retries = self.retries;
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            let mut result = job();
+            for _ in 0..retries {
+                if result.is_ok() {
+                    break;
+                }
+                result = job();
+            }
+            result
+        });
+        self.tasks.push_back((name, task));
+        true
+    }
+
+    /// Whether `Backpressure::SlowSimulation` holds the simulation, nothing new is captured then
+    pub fn is_slowing_simulation(&self) -> bool {
+        self.paused_simulation
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.tasks.len() >= self.capacity.max(1)
+    }
+
+    /// Number of writes in flight
+    pub fn pending(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Blocks until every queued write is on disk
+    pub fn flush(&mut self) {
+        if !self.tasks.is_empty() {
+            info!("Flushing {} image writes", self.tasks.len());
+        }
+        while !self.tasks.is_empty() {
+            self.finish_oldest();
+        }
+    }
+
+    /// Failed writes since the last call
+    pub fn take_failures(&mut self) -> Vec<SegmentationError> {
+        std::mem::take(&mut self.failures)
+    }
+
+    fn finish_oldest(&mut self) {
+        if let Some((name, task)) = self.tasks.pop_front() {
+            if let Err(e) = block_on(task) {
+                debug!("Write of {} failed", name);
+                self.failures.push(e);
+            }
+        }
+    }
+
+    /// Drops the writes that finished
+    fn poll(&mut self) {
+        let failures = &mut self.failures;
+        self.tasks.retain_mut(|(name, task)| match block_on(future::poll_once(task)) {
+            Some(result) => {
+                if let Err(e) = result {
+                    debug!("Write of {} failed", name);
+                    failures.push(e);
+                }
+                false
+            }
+            None => true,
+        });
+    }
+}
+
+impl Drop for ImageWriter {
+    fn drop(&mut self) {
+        // dropping a task cancels it, finish the writes instead
+        self.flush();
+        // no events after the app is gone
+        for e in self.take_failures() {
+            error!("Capture failed: {}", e);
+        }
+    }
+}
+
+fn poll_image_writer(
+    mut writer: ResMut<ImageWriter>,
+    mut time: ResMut<Time<Virtual>>,
+    mut failures: EventWriter<CaptureFailed>,
+) {
+    writer.poll();
+    failures.send_batch(writer.take_failures().into_iter().map(|error| CaptureFailed { error }));
+
+    if writer.backpressure == Backpressure::SlowSimulation && writer.is_full() {
+        if !time.is_paused() {
+            debug!("Image writer full, pausing simulation");
+            time.pause();
+            writer.paused_simulation = true;
+        }
+    } else if writer.paused_simulation {
+        time.unpause();
+        writer.paused_simulation = false;
+    }
+}
+
+pub(crate) fn flush_image_writer(mut writer: ResMut<ImageWriter>, mut failures: EventWriter<CaptureFailed>) {
+    writer.flush();
+    failures.send_batch(writer.take_failures().into_iter().map(|error| CaptureFailed { error }));
+}
@@ -1,9 +1,7 @@
 use bevy::color::Color;
 
 
-pub fn random_color() -> Color {
-    use rand::Rng;
-    let mut rng = rand::thread_rng();
+pub fn random_color(rng: &mut impl rand::Rng) -> Color {
     let r: f32 = rng.gen(); // Generates a random float between 0.0 and 1.0
     let g: f32 = rng.gen();
     let b: f32 = rng.gen();
@@ -11,4 +9,7 @@ pub fn random_color() -> Color {
     Color::srgb(r, g, b)
 }
 
-pub mod image_copy;
\ No newline at end of file
+pub mod encoding;
+pub mod hdr;
+pub mod image_copy;
+pub mod image_writer;
\ No newline at end of file
@@ -0,0 +1,272 @@
+//! Segmentation View
+//!
+//! Live display of the segmentation masks on window cameras. While the view is visible the mask
+//! is either swapped in full screen, shown next to the RGB image, shown as a picture in picture
+//! or blended on top of the RGB image. A legend lists the class names and colors from the
+//! `SegmentationDataTable`.
+//!
+//! Everything but full screen is drawn with UI nodes from the offscreen `CaptureCamera` and
+//! `SegmentationCamera` targets, so the overlay lines up with the window for perspective cameras
+//! that share the window's vertical field of view.
+
+use bevy::{
+    prelude::*,
+    render::camera::RenderTarget,
+    ui::TargetCamera,
+};
+
+use crate::{
+    components::*,
+    input::ToggleSegmentationView,
+    resources::SegmentationDataTable,
+};
+
+/// Shows the segmentation view of window cameras
+pub struct SegmentationViewPlugin;
+
+impl Plugin for SegmentationViewPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<SegmentationView>()
+            .add_systems(
+                Update,
+                (
+                    toggle_segmentation_view,
+                    update_segmentation_view.run_if(
+                        resource_changed::<SegmentationView>
+                            .or_else(resource_changed::<SegmentationDataTable>),
+                    ),
+                ).chain(),
+            );
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SegmentationViewMode {
+    /// Swaps the whole window to the mask
+    #[default]
+    FullScreen,
+    /// RGB image on the left, mask on the right
+    SplitScreen,
+    /// Small mask in the top right corner of the RGB image
+    PictureInPicture,
+    /// Mask blended on top of the RGB image
+    Overlay,
+}
+
+impl SegmentationViewMode {
+    pub fn next(&self) -> Self {
+        match self {
+            SegmentationViewMode::FullScreen => SegmentationViewMode::SplitScreen,
+            SegmentationViewMode::SplitScreen => SegmentationViewMode::PictureInPicture,
+            SegmentationViewMode::PictureInPicture => SegmentationViewMode::Overlay,
+            SegmentationViewMode::Overlay => SegmentationViewMode::FullScreen,
+        }
+    }
+}
+
+/// How and whether the segmentation view is displayed on window cameras
+#[derive(Resource)]
+pub struct SegmentationView {
+    pub mode: SegmentationViewMode,
+    /// Opacity of the mask in `SegmentationViewMode::Overlay`
+    pub overlay_alpha: f32,
+    pub show_legend: bool,
+    pub visible: bool,
+}
+
+impl Default for SegmentationView {
+    fn default() -> Self {
+        SegmentationView {
+            mode: SegmentationViewMode::default(),
+            overlay_alpha: 0.5,
+            show_legend: true,
+            visible: false,
+        }
+    }
+}
+
+/// Root of the UI spawned for the segmentation view
+#[derive(Component)]
+struct SegmentationViewUi;
+
+fn toggle_segmentation_view(
+    mut view_toggles: EventReader<ToggleSegmentationView>,
+    mut view: ResMut<SegmentationView>,
+) {
+    for toggle in view_toggles.read() {
+        match toggle {
+            ToggleSegmentationView::Show => view.visible = true,
+            ToggleSegmentationView::Hide => view.visible = false,
+            ToggleSegmentationView::Toggle => view.visible = !view.visible,
+            ToggleSegmentationView::SetMode(mode) => view.mode = *mode,
+            ToggleSegmentationView::CycleMode => {
+                view.mode = view.mode.next();
+                info!("Segmentation view mode {:?}", view.mode);
+            }
+        }
+    }
+}
+
+fn image_target(camera: &Camera) -> Option<Handle<Image>> {
+    match &camera.target {
+        RenderTarget::Image(handle) => Some(handle.clone()),
+        _ => None,
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn update_segmentation_view(
+    mut commands: Commands,
+    view: Res<SegmentationView>,
+    object_table: Res<SegmentationDataTable>,
+    rgb_query: Query<(Entity, &Camera, &RGBCamera)>,
+    mut segmentation_query: Query<
+        (&Parent, &mut Camera),
+        (With<SegmentationCamera>, Without<RGBCamera>),
+    >,
+    capture_query: Query<
+        (&Parent, &Camera),
+        (With<CaptureCamera>, Without<SegmentationCamera>, Without<RGBCamera>),
+    >,
+    ui_query: Query<Entity, With<SegmentationViewUi>>,
+) {
+    // full screen just renders the mask twin straight to the window
+    for (_, mut camera) in segmentation_query.iter_mut() {
+        if let RenderTarget::Window(_) = camera.target {
+            camera.is_active = view.visible && view.mode == SegmentationViewMode::FullScreen;
+        }
+    }
+
+    for entity in ui_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    if !view.visible {
+        return;
+    }
+
+    for (entity, camera, camera_description) in rgb_query.iter() {
+        if !matches!(camera.target, RenderTarget::Window(_)) {
+            continue;
+        }
+
+        let mask = segmentation_query
+            .iter()
+            .find(|(parent, _)| parent.get() == entity)
+            .and_then(|(_, camera)| image_target(camera));
+        let capture = capture_query
+            .iter()
+            .find(|(parent, _)| parent.get() == entity)
+            .and_then(|(_, camera)| image_target(camera));
+
+        let aspect_ratio = camera_description.width as f32 / camera_description.height as f32;
+
+        commands.spawn((NodeBundle {
+            style: Style {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            ..default()
+        }, TargetCamera(entity), SegmentationViewUi)).with_children(|root| {
+
+            match (view.mode, mask, capture) {
+                (SegmentationViewMode::SplitScreen, Some(mask), Some(capture)) => {
+                    for image in [capture, mask] {
+                        root.spawn(ImageBundle {
+                            style: Style {
+                                width: Val::Percent(50.0),
+                                aspect_ratio: Some(aspect_ratio),
+                                ..default()
+                            },
+                            image: UiImage::new(image),
+                            ..default()
+                        });
+                    }
+                }
+                (SegmentationViewMode::PictureInPicture, Some(mask), _) => {
+                    root.spawn(ImageBundle {
+                        style: Style {
+                            position_type: PositionType::Absolute,
+                            top: Val::Px(10.0),
+                            right: Val::Px(10.0),
+                            width: Val::Percent(30.0),
+                            aspect_ratio: Some(aspect_ratio),
+                            ..default()
+                        },
+                        image: UiImage::new(mask),
+                        ..default()
+                    });
+                }
+                (SegmentationViewMode::Overlay, Some(mask), _) => {
+                    // same vertical field of view as the window, centered
+                    root.spawn(ImageBundle {
+                        style: Style {
+                            height: Val::Percent(100.0),
+                            aspect_ratio: Some(aspect_ratio),
+                            ..default()
+                        },
+                        image: UiImage::new(mask)
+                            .with_color(Color::srgba(1.0, 1.0, 1.0, view.overlay_alpha)),
+                        ..default()
+                    });
+                }
+                _ => {}
+            }
+
+            if view.show_legend {
+                spawn_legend(root, &object_table);
+            }
+        });
+    }
+}
+
+fn spawn_legend(root: &mut ChildBuilder, object_table: &SegmentationDataTable) {
+    root.spawn(NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            left: Val::Px(10.0),
+            flex_direction: FlexDirection::Column,
+            padding: UiRect::all(Val::Px(6.0)),
+            row_gap: Val::Px(4.0),
+            ..default()
+        },
+        background_color: Color::srgba(0.0, 0.0, 0.0, 0.6).into(),
+        ..default()
+    }).with_children(|legend| {
+        for (label, color) in object_table.classes() {
+            legend.spawn(NodeBundle {
+                style: Style {
+                    align_items: AlignItems::Center,
+                    column_gap: Val::Px(6.0),
+                    ..default()
+                },
+                ..default()
+            }).with_children(|row| {
+                row.spawn(NodeBundle {
+                    style: Style {
+                        width: Val::Px(14.0),
+                        height: Val::Px(14.0),
+                        border: UiRect::all(Val::Px(1.0)),
+                        ..default()
+                    },
+                    background_color: (*color).into(),
+                    border_color: Color::WHITE.into(),
+                    ..default()
+                });
+                row.spawn(TextBundle::from_section(
+                    label.clone(),
+                    TextStyle {
+                        font_size: 14.0,
+                        color: Color::WHITE,
+                        ..default()
+                    },
+                ));
+            });
+        }
+    });
+}
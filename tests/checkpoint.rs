@@ -0,0 +1,127 @@
+use bevy_image_segmentation::{
+    job::{JobPlugin, JobSpec},
+    resources::{Checkpoint, RunOffset, Shard},
+};
+use serde_json::{json, Map};
+
+#[test]
+fn parse_shards() {
+    assert_eq!("0/4".parse::<Shard>(), Ok(Shard { index: 0, count: 4 }));
+    assert_eq!("3/4".parse::<Shard>(), Ok(Shard { index: 3, count: 4 }));
+    assert_eq!(" 1 / 2 ".parse::<Shard>(), Ok(Shard { index: 1, count: 2 }));
+    assert_eq!(Shard { index: 2, count: 5 }.to_string().parse::<Shard>(), Ok(Shard { index: 2, count: 5 }));
+
+    // the index counts from 0, so it has to stay below the count
+    assert!("4/4".parse::<Shard>().is_err());
+    assert!("0/0".parse::<Shard>().is_err());
+    assert!("1/0".parse::<Shard>().is_err());
+    assert!("1".parse::<Shard>().is_err());
+    assert!("a/4".parse::<Shard>().is_err());
+    assert!("-1/4".parse::<Shard>().is_err());
+}
+
+#[test]
+fn shard_ranges_cover_every_index_once() {
+    for frames in [0, 1, 7, 10, 100, 101] {
+        for count in 1..=8 {
+            let mut next = 0;
+            for index in 0..count {
+                let range = Shard { index, count }.range(frames);
+                assert_eq!(range.start, next, "{}/{} of {}", index, count, frames);
+                assert!(range.len() <= frames.div_ceil(count) as usize, "{}/{} of {}", index, count, frames);
+                assert!(range.len() >= (frames / count) as usize, "{}/{} of {}", index, count, frames);
+                next = range.end;
+            }
+            assert_eq!(next, frames, "{} shards of {}", count, frames);
+        }
+    }
+}
+
+#[test]
+fn shard_file_suffix() {
+    assert_eq!(Shard::file_suffix(None), "");
+    assert_eq!(Shard::file_suffix(Some(Shard { index: 1, count: 4 })), "_1of4");
+}
+
+#[test]
+fn checkpoint_round_trip() {
+    let dir = std::env::temp_dir().join(format!("bevy_image_segmentation_checkpoint_{}", std::process::id()));
+    let path = dir.join("run").join("checkpoint_1of4.json");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    assert_eq!(Checkpoint::load(&path).unwrap(), None);
+
+    let mut exporters = Map::new();
+    exporters.insert("splits".to_string(), json!({ "train": 12, "val": 2, "test": 1 }));
+    exporters.insert("dataset".to_string(), json!(25));
+    let checkpoint = Checkpoint {
+        seed: u64::MAX,
+        shard: Some(Shard { index: 1, count: 4 }),
+        next_index: 40,
+        next_step: 80,
+        exporters,
+    };
+
+    checkpoint.save(&path).unwrap();
+    assert_eq!(Checkpoint::load(&path).unwrap(), Some(checkpoint));
+    // saved through a temporary file that is renamed
+    assert!(!dir.join("run").join("checkpoint_1of4.json.tmp").exists());
+
+    std::fs::write(&path, b"{ \"seed\": ").unwrap();
+    assert!(Checkpoint::load(&path).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Steps and indices two shards capture when the given capture numbers (counted from the first
+/// capture of each shard) are dropped
+fn shard_captures(shard: Shard, dropped: &[u32]) -> Vec<(u32, u32)> {
+    let spec = JobSpec::from_toml(
+        "name = \"sharded\"\n[capture]\nframes = 11\npolicy = { EveryNFrames = 3 }\n",
+    )
+    .unwrap();
+    let job = JobPlugin::new(spec).with_shard(shard).unwrap();
+    let offset = job.offset();
+
+    // headless runs stop once the last index of the shard was captured
+    let mut captures = Vec::new();
+    for capture in 0.. {
+        let step = offset.step + capture * 3;
+        let index = offset.capture_index(step, 3);
+        if index >= offset.index + job.remaining_frames() {
+            break;
+        }
+        if !dropped.contains(&capture) {
+            captures.push((step, index));
+        }
+    }
+    captures
+}
+
+#[test]
+fn shards_capture_disjoint_steps_and_indices() {
+    let first = shard_captures(Shard { index: 0, count: 2 }, &[]);
+    let second = shard_captures(Shard { index: 1, count: 2 }, &[]);
+    assert_eq!(first.len() + second.len(), 11);
+
+    let mut all: Vec<(u32, u32)> = first.iter().chain(second.iter()).copied().collect();
+    all.sort();
+    for (capture, (step, index)) in all.iter().enumerate() {
+        assert_eq!((*step, *index), (capture as u32 * 3, capture as u32), "as in a single run");
+    }
+
+    // a dropped frame leaves a gap instead of running into the indices of the next shard
+    let dropped = shard_captures(Shard { index: 0, count: 2 }, &[1]);
+    assert_eq!(dropped.len(), first.len() - 1);
+    assert!(dropped.iter().all(|capture| first.contains(capture)));
+    assert!(dropped.iter().all(|capture| !second.contains(capture)));
+}
+
+#[test]
+fn resumed_runs_keep_their_indices() {
+    let offset = RunOffset { index: 40, step: 120 };
+    assert_eq!(offset.capture_index(120, 3), 40);
+    assert_eq!(offset.capture_index(126, 3), 42);
+    // Continuous
+    assert_eq!(RunOffset { index: 5, step: 5 }.capture_index(9, 1), 9);
+}
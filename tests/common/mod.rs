@@ -0,0 +1,25 @@
+//! Fixture shared by the encoding tests
+
+use bevy::render::{
+    render_asset::RenderAssetUsages,
+    render_resource::{Extent3d, TextureDimension, TextureFormat},
+    texture::Image,
+};
+use std::path::PathBuf;
+
+pub const WIDTH: u32 = 5;
+pub const HEIGHT: u32 = 3;
+
+pub fn image(format: TextureFormat, data: Vec<u8>) -> Image {
+    Image::new(
+        Extent3d { width: WIDTH, height: HEIGHT, depth_or_array_layers: 1 },
+        TextureDimension::D2,
+        data,
+        format,
+        RenderAssetUsages::MAIN_WORLD,
+    )
+}
+
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("bevy_image_segmentation_{}_{}", std::process::id(), name))
+}
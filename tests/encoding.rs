@@ -0,0 +1,153 @@
+mod common;
+
+use bevy::render::{render_resource::TextureFormat, texture::Image};
+use bevy_image_segmentation::{
+    components::Modality,
+    error::SegmentationError,
+    materials::DEPTH_TEXTURE_FORMAT,
+    utils::encoding::{save_disparity, save_image, Encoding},
+};
+use common::{image, temp_path, HEIGHT, WIDTH};
+use image::{ImageBuffer, Luma};
+
+/// Splits an NPY file into its header dictionary and data, checking the framing on the way
+fn parse_npy(npy: &[u8]) -> (String, Vec<u8>) {
+    assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00", "magic and version 1.0");
+    let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+    assert_eq!((10 + header_len) % 64, 0, "the data starts 64 byte aligned");
+
+    let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+    assert!(header.ends_with('\n'));
+    (header.trim_end().to_string(), npy[10 + header_len..].to_vec())
+}
+
+fn saved_npy(image: &Image, modality: Modality, name: &str) -> (String, Vec<u8>) {
+    let path = temp_path(name);
+    save_image(image, modality, Encoding::Npy, &path).unwrap();
+    let npy = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    parse_npy(&npy)
+}
+
+#[test]
+fn parse_encodings() {
+    let cases = [
+        ("png", Encoding::Png { compression: 1 }),
+        ("png:6", Encoding::Png { compression: 6 }),
+        ("PNG:42", Encoding::Png { compression: 9 }),
+        ("jpeg", Encoding::Jpeg { quality: 90 }),
+        ("jpg:95", Encoding::Jpeg { quality: 95 }),
+        ("jpeg:0", Encoding::Jpeg { quality: 1 }),
+        ("webp", Encoding::WebP),
+        ("exr", Encoding::Exr),
+        ("npy", Encoding::Npy),
+        ("zstd", Encoding::Zstd { level: 3 }),
+        ("zstd:-5", Encoding::Zstd { level: -5 }),
+    ];
+    for (text, encoding) in cases {
+        assert_eq!(text.parse::<Encoding>(), Ok(encoding), "{}", text);
+        assert_eq!(encoding.to_string().parse::<Encoding>(), Ok(encoding), "{}", text);
+    }
+
+    for text in ["", "gif", "png:", "png:fast", "webp:3", "exr:1", "npy:2", "zstd:high"] {
+        assert!(text.parse::<Encoding>().is_err(), "{}", text);
+    }
+}
+
+#[test]
+fn encodings_in_job_files() {
+    assert_eq!(serde_json::from_str::<Encoding>("\"jpeg:95\"").unwrap(), Encoding::Jpeg { quality: 95 });
+    assert_eq!(serde_json::to_string(&Encoding::Zstd { level: 7 }).unwrap(), "\"zstd:7\"");
+    assert!(serde_json::from_str::<Encoding>("\"tiff\"").is_err());
+}
+
+#[test]
+fn npy_of_float_images() {
+    let values: Vec<f32> = (0..WIDTH * HEIGHT).map(|i| i as f32 * 0.5 - 3.0).collect();
+    let data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+
+    let (header, array) = saved_npy(&image(TextureFormat::R32Float, data.clone()), Modality::Rgb, "float.npy");
+    assert_eq!(header, "{'descr': '<f4', 'fortran_order': False, 'shape': (3, 5), }");
+    assert_eq!(array, data);
+}
+
+#[test]
+fn npy_of_color_images() {
+    let data: Vec<u8> = (0..WIDTH * HEIGHT * 4).map(|i| i as u8).collect();
+
+    let (header, array) = saved_npy(&image(TextureFormat::Rgba8UnormSrgb, data.clone()), Modality::Rgb, "rgba.npy");
+    assert_eq!(header, "{'descr': '|u1', 'fortran_order': False, 'shape': (3, 5, 4), }");
+    assert_eq!(array, data);
+
+    // BGRA is saved as RGBA
+    let (header, array) = saved_npy(&image(TextureFormat::Bgra8UnormSrgb, data.clone()), Modality::Rgb, "bgra.npy");
+    assert_eq!(header, "{'descr': '|u1', 'fortran_order': False, 'shape': (3, 5, 4), }");
+    for (bgra, rgba) in data.chunks_exact(4).zip(array.chunks_exact(4)) {
+        assert_eq!(rgba, [bgra[2], bgra[1], bgra[0], bgra[3]]);
+    }
+}
+
+#[test]
+fn zstd_holds_the_npy() {
+    let data: Vec<u8> = (0..WIDTH * HEIGHT * 2).map(|i| (i * 3) as u8).collect();
+    let image = image(TextureFormat::R16Uint, data.clone());
+
+    let path = temp_path("depth.npy.zst");
+    save_image(&image, Modality::Rgb, Encoding::Zstd { level: 3 }, &path).unwrap();
+    let npy = zstd::decode_all(&std::fs::read(&path).unwrap()[..]).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let (header, array) = parse_npy(&npy);
+    assert_eq!(header, "{'descr': '<u2', 'fortran_order': False, 'shape': (3, 5), }");
+    assert_eq!(array, data);
+}
+
+#[test]
+fn npy_of_disparity() {
+    let disparity = ImageBuffer::<Luma<u16>, Vec<u16>>::from_fn(WIDTH, HEIGHT, |x, y| Luma([(x * 1000 + y) as u16]));
+    let path = temp_path("disparity.npy");
+    save_disparity(disparity.clone(), Encoding::Npy, &path).unwrap();
+    let (header, array) = parse_npy(&std::fs::read(&path).unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(header, "{'descr': '<u2', 'fortran_order': False, 'shape': (3, 5), }");
+    let expected: Vec<u8> = disparity.iter().flat_map(|d| d.to_le_bytes()).collect();
+    assert_eq!(array, expected);
+
+    // lossy encodings would change the disparity
+    assert!(save_disparity(disparity, Encoding::Jpeg { quality: 100 }, &path).is_err());
+}
+
+#[test]
+fn npy_of_unsupported_formats_fails() {
+    let path = temp_path("unsupported.npy");
+    let image = image(TextureFormat::Rg16Float, vec![0; (WIDTH * HEIGHT * 4) as usize]);
+    assert!(matches!(
+        save_image(&image, Modality::Rgb, Encoding::Npy, &path),
+        Err(SegmentationError::UnsupportedFormat { format: TextureFormat::Rg16Float, .. })
+    ));
+    assert!(!path.exists());
+}
+
+#[test]
+fn depth_is_saved_decoded() {
+    // coarse depth plus residual, see `DepthMaterial`
+    let depths: Vec<f32> = (0..WIDTH * HEIGHT).map(|i| i as f32 + 0.25).collect();
+    let data = (0..WIDTH * HEIGHT)
+        .flat_map(|i| [i as f32, 0.25, 0.0, 1.0])
+        .flat_map(f32::to_le_bytes)
+        .collect();
+    let depth = image(DEPTH_TEXTURE_FORMAT, data);
+
+    let (header, array) = saved_npy(&depth, Modality::Depth, "depth.npy");
+    assert_eq!(header, "{'descr': '<f4', 'fortran_order': False, 'shape': (3, 5), }");
+    assert_eq!(array, depths.iter().flat_map(|d| d.to_le_bytes()).collect::<Vec<u8>>());
+
+    // 8 bit encodings would write the packed channels
+    let path = temp_path("depth.png");
+    assert!(matches!(
+        save_image(&depth, Modality::Depth, Encoding::Png { compression: 1 }, &path),
+        Err(SegmentationError::UnsupportedFormat { format: TextureFormat::R32Float, .. })
+    ));
+    assert!(!path.exists());
+}
@@ -0,0 +1,88 @@
+use bevy::prelude::*;
+use bevy_image_segmentation::{
+    components::Modality,
+    error::SegmentationError,
+    resources::CameraOutputTable,
+    utils::image_copy::ImageReadback,
+};
+use crossbeam_channel::Sender;
+
+/// A table with the RGB and segmentation output of one camera
+fn table() -> (CameraOutputTable, Vec<Sender<ImageReadback>>) {
+    let mut table = CameraOutputTable::default();
+    let senders = [Modality::Rgb, Modality::Segmentation]
+        .into_iter()
+        .map(|modality| table.link_new_target(Handle::default(), "front", modality).1)
+        .collect();
+    (table, senders)
+}
+
+fn send(senders: &[Sender<ImageReadback>], camera_id: usize, frame: u32) {
+    senders[camera_id]
+        .send(ImageReadback { frame, camera_id, data: vec![frame as u8, camera_id as u8] })
+        .unwrap();
+}
+
+/// Frames of every bundle the table hands out
+fn collect(table: &mut CameraOutputTable) -> Vec<u32> {
+    std::iter::from_fn(|| table.collect_frame_bundle())
+        .map(|(frame, data)| {
+            assert_eq!(data, vec![vec![frame as u8, 0], vec![frame as u8, 1]], "data in camera id order");
+            frame
+        })
+        .collect()
+}
+
+#[test]
+fn frames_completed_together_are_all_collected_oldest_first() {
+    let (mut table, senders) = table();
+    send(&senders, 0, 5);
+    send(&senders, 0, 6);
+    send(&senders, 1, 6);
+    send(&senders, 1, 5);
+
+    assert!(table.receive_readbacks().is_empty());
+    assert_eq!(collect(&mut table), vec![5, 6]);
+}
+
+#[test]
+fn out_of_order_readbacks_complete_their_frames() {
+    let (mut table, senders) = table();
+    send(&senders, 0, 2);
+    send(&senders, 1, 3);
+    send(&senders, 0, 3);
+    assert!(table.receive_readbacks().is_empty());
+    assert_eq!(collect(&mut table), vec![3]);
+
+    // frame 2 still completes after a newer frame was collected
+    send(&senders, 1, 2);
+    assert!(table.receive_readbacks().is_empty());
+    assert_eq!(collect(&mut table), vec![2]);
+    assert_eq!(collect(&mut table), Vec::<u32>::new());
+}
+
+#[test]
+fn frames_that_never_complete_are_reported() {
+    let (mut table, senders) = table();
+    // the segmentation camera had no free staging buffer in frame 1
+    send(&senders, 0, 1);
+    for frame in 2..=40 {
+        send(&senders, 0, frame);
+        send(&senders, 1, frame);
+    }
+
+    let incomplete = table.receive_readbacks();
+    assert_eq!(incomplete.len(), 1);
+    match &incomplete[0] {
+        SegmentationError::IncompleteFrame { frame, missing } => {
+            assert_eq!(*frame, 1);
+            assert_eq!(missing, &vec!["front_segmentation".to_string()]);
+        }
+        error => panic!("unexpected error {}", error),
+    }
+
+    // complete frames wait however old they are
+    assert_eq!(collect(&mut table), (2..=40).collect::<Vec<_>>());
+    assert!(table.receive_readbacks().is_empty());
+}
+
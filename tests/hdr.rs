@@ -0,0 +1,161 @@
+mod common;
+
+use bevy::render::{render_resource::TextureFormat, texture::Image};
+use bevy_image_segmentation::{
+    error::SegmentationError,
+    utils::{
+        encoding::Encoding,
+        hdr::{f16_to_f32, tonemapped_preview, write_exr},
+    },
+};
+use common::{image, temp_path, HEIGHT, WIDTH};
+use exr::prelude::{read_first_flat_layer_from_file, Compression, FlatSamples, Vec2};
+
+/// Every sample of the image is distinct, channel `c` of pixel `i` holds `i + c / 4`
+fn samples() -> Vec<f32> {
+    (0..WIDTH * HEIGHT * 4).map(|i| (i / 4) as f32 + (i % 4) as f32 / 4.0).collect()
+}
+
+/// Writes `image` and reads it back with the `exr` decoder, returns the name, whether it holds
+/// half floats and the samples of every channel in row-major order
+fn read_back(image: &Image, name: &str) -> Vec<(String, bool, Vec<f32>)> {
+    let path = temp_path(&format!("{}.exr", name));
+    write_exr(&path, image).unwrap();
+    let exr = read_first_flat_layer_from_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let size = Vec2(WIDTH as usize, HEIGHT as usize);
+    assert_eq!(exr.attributes.display_window.size, size);
+    assert_eq!(exr.layer_data.size, size);
+    assert_eq!(exr.layer_data.encoding.compression, Compression::Uncompressed);
+
+    exr.layer_data
+        .channel_data
+        .list
+        .iter()
+        .map(|channel| {
+            let half = matches!(channel.sample_data, FlatSamples::F16(_));
+            (channel.name.to_string(), half, channel.sample_data.values_as_f32().collect())
+        })
+        .collect()
+}
+
+fn check_round_trip(format: TextureFormat, half: bool, encode: impl Fn(f32) -> Vec<u8>) {
+    let samples = samples();
+    let data: Vec<u8> = samples.iter().copied().flat_map(&encode).collect();
+    let channels = read_back(&image(format, data), &format!("{:?}", format));
+
+    // channels are stored sorted by name, each holds one component of every pixel
+    let names: Vec<&str> = channels.iter().map(|(name, _, _)| name.as_str()).collect();
+    assert_eq!(names, ["A", "B", "G", "R"]);
+    for ((_, is_half, values), component) in channels.iter().zip([3, 2, 1, 0]) {
+        assert_eq!(*is_half, half);
+        let expected: Vec<f32> = samples.iter().skip(component).step_by(4).copied().collect();
+        assert_eq!(*values, expected, "component {}", component);
+    }
+}
+
+#[test]
+fn exr_round_trip_float() {
+    check_round_trip(TextureFormat::Rgba32Float, false, |value| value.to_le_bytes().to_vec());
+}
+
+#[test]
+fn exr_round_trip_half() {
+    // the samples are small multiples of a quarter, exact in half precision
+    check_round_trip(TextureFormat::Rgba16Float, true, |value| {
+        let bits = if value == 0.0 {
+            0
+        } else {
+            let exponent = value.log2().floor() as i32;
+            let mantissa = ((value / (exponent as f32).exp2() - 1.0) * 1024.0) as u16;
+            (((exponent + 15) as u16) << 10) | mantissa
+        };
+        assert_eq!(f16_to_f32(bits), value);
+        bits.to_le_bytes().to_vec()
+    });
+}
+
+#[test]
+fn exr_round_trip_depth() {
+    let depths: Vec<f32> = (0..WIDTH * HEIGHT).map(|i| i as f32 * 0.75 + 0.1).collect();
+    let data = depths.iter().flat_map(|depth| depth.to_le_bytes()).collect();
+    let channels = read_back(&image(TextureFormat::R32Float, data), "depth");
+
+    assert_eq!(channels, [("Y".to_string(), false, depths)]);
+}
+
+#[test]
+fn exr_rejects_other_formats_and_sizes() {
+    let path = temp_path("rejected.exr");
+
+    let ldr = image(TextureFormat::Rgba8UnormSrgb, vec![0; (WIDTH * HEIGHT * 4) as usize]);
+    assert!(matches!(
+        write_exr(&path, &ldr),
+        Err(SegmentationError::UnsupportedFormat { encoding: Encoding::Exr, .. })
+    ));
+
+    let mut short = image(TextureFormat::Rgba32Float, vec![0; (WIDTH * HEIGHT * 16) as usize]);
+    short.data.truncate(8);
+    assert!(write_exr(&path, &short).is_err());
+    assert!(!path.exists());
+}
+
+#[test]
+fn half_floats() {
+    assert_eq!(f16_to_f32(0x0000).to_bits(), 0.0f32.to_bits());
+    assert_eq!(f16_to_f32(0x8000).to_bits(), (-0.0f32).to_bits());
+    assert_eq!(f16_to_f32(0x3c00), 1.0);
+    assert_eq!(f16_to_f32(0xc000), -2.0);
+    assert_eq!(f16_to_f32(0x3555), 0.333_251_95);
+    assert_eq!(f16_to_f32(0x7bff), 65504.0);
+
+    // subnormals, the smallest, the largest and a negative one
+    assert_eq!(f16_to_f32(0x0001), (-24f32).exp2());
+    assert_eq!(f16_to_f32(0x03ff), 1023.0 * (-24f32).exp2());
+    assert_eq!(f16_to_f32(0x8200), -512.0 * (-24f32).exp2());
+    // smallest normal
+    assert_eq!(f16_to_f32(0x0400), (-14f32).exp2());
+
+    assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+    assert_eq!(f16_to_f32(0xfc00), f32::NEG_INFINITY);
+    assert!(f16_to_f32(0x7e00).is_nan());
+    assert!(f16_to_f32(0xfc01).is_nan());
+}
+
+#[test]
+fn previews_are_tonemapped() {
+    let pixels: [[f32; 4]; 5] = [
+        [0.0, 0.0, 0.0, 1.0],
+        [1.0, 1.0, 1.0, 1.0],
+        [1000.0, 0.5, -3.0, 0.5],
+        [0.01, 0.1, 10.0, 2.0],
+        [f32::INFINITY, 0.0, 0.0, 0.0],
+    ];
+    let data: Vec<u8> = pixels
+        .iter()
+        .cycle()
+        .take((WIDTH * HEIGHT) as usize)
+        .flatten()
+        .flat_map(|value| value.to_le_bytes())
+        .collect();
+    let hdr = image(TextureFormat::Rgba32Float, data);
+
+    let preview = tonemapped_preview(&hdr, 0.0).unwrap();
+    assert_eq!(preview.dimensions(), (WIDTH, HEIGHT));
+    assert_eq!(preview.get_pixel(0, 0).0, [0, 0, 0, 255]);
+    // Reinhard maps 1 to 0.5, which is 188 in sRGB
+    assert_eq!(preview.get_pixel(1, 0).0, [188, 188, 188, 255]);
+    // bright values approach white, negative ones are black, alpha is clamped
+    assert_eq!(preview.get_pixel(2, 0).0[0], 255);
+    assert_eq!(preview.get_pixel(2, 0).0[2], 0);
+    assert_eq!(preview.get_pixel(2, 0).0[3], 128);
+    assert_eq!(preview.get_pixel(3, 0).0[3], 255);
+
+    // every stop of exposure brightens
+    let brighter = tonemapped_preview(&hdr, 1.0).unwrap();
+    assert!(brighter.get_pixel(1, 0).0[0] > 188);
+
+    let ldr = image(TextureFormat::Rgba8UnormSrgb, vec![0; (WIDTH * HEIGHT * 4) as usize]);
+    assert!(tonemapped_preview(&ldr, 0.0).is_none());
+}
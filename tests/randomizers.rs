@@ -0,0 +1,119 @@
+use bevy::prelude::*;
+use bevy_image_segmentation::{
+    randomizers::{MaterialRandomizer, PointLightRandomizer, PoseRandomizer, Randomizer},
+    resources::SegmentationRng,
+    SegmentationObject,
+};
+use serde_json::Value;
+
+const OBJECTS: [&str; 3] = ["car", "tree", "car"];
+
+/// Objects of `OBJECTS` with their own materials and two point lights, spawned in `order`
+fn scene(order: &[usize]) -> World {
+    let mut world = World::new();
+    world.init_resource::<Assets<StandardMaterial>>();
+
+    for &i in order {
+        let material = world.resource_mut::<Assets<StandardMaterial>>().add(StandardMaterial::default());
+        world.spawn((
+            SegmentationObject(OBJECTS[i].to_string()),
+            Name::new(format!("{}{}", OBJECTS[i], i)),
+            Transform::from_xyz(i as f32, 0.0, 0.0),
+            Visibility::default(),
+            material,
+        ));
+    }
+    for i in 0..2 {
+        world.spawn((
+            PointLight::default(),
+            Name::new(format!("lamp{}", i)),
+            Transform::from_xyz(0.0, 3.0, i as f32),
+            Visibility::default(),
+        ));
+    }
+    world
+}
+
+/// Runs the pose, light and material randomizers on a scene and returns their parameters and
+/// the resulting pose, material and light of every named entity, sorted by name
+fn randomized(seed: u64, order: &[usize]) -> (Vec<Value>, Vec<String>) {
+    let mut world = scene(order);
+    let rng = SegmentationRng::new(seed);
+    let mut light = PointLightRandomizer::default();
+    light.count = Some([1, 2]);
+    let mut randomizers: Vec<Box<dyn Randomizer>> = vec![
+        Box::new(PoseRandomizer::default()),
+        Box::new(light),
+        Box::new(MaterialRandomizer::default()),
+    ];
+
+    let parameters = randomizers
+        .iter_mut()
+        .map(|randomizer| randomizer.randomize(&mut world, &rng))
+        .collect();
+
+    let mut query = world.query::<(
+        &Name,
+        &Transform,
+        &Visibility,
+        Option<&Handle<StandardMaterial>>,
+        Option<&PointLight>,
+    )>();
+    let materials = world.resource::<Assets<StandardMaterial>>();
+    let mut state: Vec<String> = query
+        .iter(&world)
+        .map(|(name, transform, visibility, material, light)| {
+            let material = material.and_then(|handle| materials.get(handle)).map(|material| {
+                (material.base_color, material.perceptual_roughness, material.metallic)
+            });
+            let light = light.map(|light| (light.intensity, light.color, light.shadows_enabled));
+            format!("{} {:?} {:?} {:?} {:?}", name, transform, visibility, material, light)
+        })
+        .collect();
+    state.sort();
+
+    (parameters, state)
+}
+
+#[test]
+fn seeded_runs_randomize_alike() {
+    let (parameters, state) = randomized(42, &[0, 1, 2]);
+    assert_eq!(randomized(42, &[0, 1, 2]), (parameters.clone(), state.clone()));
+
+    // every object and light got parameters
+    assert_eq!(parameters[0].as_object().unwrap().len(), 3);
+    assert_eq!(parameters[1].as_object().unwrap().len(), 2);
+    assert_eq!(parameters[2].as_object().unwrap().len(), 3);
+
+    // another seed draws other poses, lights and materials
+    let (other, _) = randomized(43, &[0, 1, 2]);
+    for (parameters, other) in parameters.iter().zip(&other) {
+        assert_ne!(parameters, other);
+    }
+}
+
+#[test]
+fn spawn_order_does_not_change_the_values() {
+    // values are keyed by name, not by entity
+    assert_eq!(randomized(42, &[0, 1, 2]), randomized(42, &[2, 1, 0]));
+}
+
+#[test]
+fn randomizing_again_does_not_accumulate() {
+    let mut world = scene(&[0, 1, 2]);
+    let rng = SegmentationRng::new(42);
+    let mut pose = PoseRandomizer::default();
+
+    let first = pose.randomize(&mut world, &rng);
+    let mut query = world.query::<(&Name, &Transform)>();
+    let mut transforms: Vec<(String, Transform)> =
+        query.iter(&world).map(|(name, transform)| (name.to_string(), *transform)).collect();
+    transforms.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    // the same step applied again starts from the base pose
+    assert_eq!(pose.randomize(&mut world, &rng), first);
+    let mut again: Vec<(String, Transform)> =
+        query.iter(&world).map(|(name, transform)| (name.to_string(), *transform)).collect();
+    again.sort_by(|(a, _), (b, _)| a.cmp(b));
+    assert_eq!(again, transforms);
+}
@@ -0,0 +1,73 @@
+use bevy::render::render_resource::{TextureAspect, TextureFormat};
+use bevy_image_segmentation::utils::image_copy::ReadbackLayout;
+
+const WIDTH: u32 = 641;
+const HEIGHT: u32 = 479;
+/// `wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`
+const ROW_ALIGNMENT: usize = 256;
+
+/// Fills a staging buffer the way `copy_texture_to_buffer` does, padding bytes are 0xff
+fn padded_buffer(layout: &ReadbackLayout) -> (Vec<u8>, Vec<u8>) {
+    let mut padded = vec![0xff; layout.buffer_size() as usize];
+    let mut image = Vec::with_capacity(layout.image_size());
+
+    for row in 0..layout.rows {
+        for byte in 0..layout.bytes_per_row {
+            let value = ((row * 7 + byte) % 251) as u8;
+            padded[row * layout.padded_bytes_per_row + byte] = value;
+            image.push(value);
+        }
+    }
+
+    (padded, image)
+}
+
+fn check_format(format: TextureFormat, block_size: usize) {
+    let layout = ReadbackLayout::new(WIDTH, HEIGHT, format).unwrap();
+
+    assert_eq!(layout.block_size, block_size, "{:?}", format);
+    assert_eq!(layout.bytes_per_row, WIDTH as usize * block_size, "{:?}", format);
+    assert_eq!(layout.padded_bytes_per_row % ROW_ALIGNMENT, 0, "{:?}", format);
+    assert!(layout.padded_bytes_per_row >= layout.bytes_per_row, "{:?}", format);
+    assert_eq!(layout.rows, HEIGHT as usize, "{:?}", format);
+    assert_eq!(layout.image_size(), (WIDTH * HEIGHT) as usize * block_size, "{:?}", format);
+
+    let (padded, image) = padded_buffer(&layout);
+    assert_eq!(layout.unpad(&padded), image, "{:?}", format);
+}
+
+#[test]
+fn odd_resolution_color_formats() {
+    check_format(TextureFormat::R8Unorm, 1);
+    check_format(TextureFormat::R16Unorm, 2);
+    check_format(TextureFormat::R32Float, 4);
+    check_format(TextureFormat::Rgba8UnormSrgb, 4);
+    check_format(TextureFormat::Rgba16Float, 8);
+    check_format(TextureFormat::Rgba32Float, 16);
+}
+
+#[test]
+fn odd_resolution_depth_formats() {
+    check_format(TextureFormat::Depth16Unorm, 2);
+    check_format(TextureFormat::Depth32Float, 4);
+
+    // only the depth aspect is read back
+    check_format(TextureFormat::Depth32FloatStencil8, 4);
+    let layout = ReadbackLayout::new(WIDTH, HEIGHT, TextureFormat::Depth32FloatStencil8).unwrap();
+    assert_eq!(layout.aspect, TextureAspect::DepthOnly);
+}
+
+#[test]
+fn uncopyable_formats() {
+    assert_eq!(ReadbackLayout::new(WIDTH, HEIGHT, TextureFormat::Depth24Plus), None);
+    assert_eq!(ReadbackLayout::new(WIDTH, HEIGHT, TextureFormat::Depth24PlusStencil8), None);
+}
+
+#[test]
+fn aligned_width_has_no_padding() {
+    let layout = ReadbackLayout::new(640, HEIGHT, TextureFormat::Rgba8UnormSrgb).unwrap();
+    assert_eq!(layout.bytes_per_row, layout.padded_bytes_per_row);
+
+    let (padded, image) = padded_buffer(&layout);
+    assert_eq!(layout.unpad(&padded), image);
+}
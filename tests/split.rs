@@ -0,0 +1,115 @@
+use bevy_image_segmentation::resources::{Split, SplitKey, SplitPolicy};
+use serde_json::{json, Map, Value};
+
+fn policy(train: f32, val: f32, test: f32, key: SplitKey) -> SplitPolicy {
+    SplitPolicy { train, val, test, key }
+}
+
+fn entries(value: Value) -> Map<String, Value> {
+    value.as_object().unwrap().clone()
+}
+
+/// Shares of train, val and test over `captures` captures
+fn shares(policy: &SplitPolicy, captures: u32) -> [f32; 3] {
+    let mut counts = [0; 3];
+    for index in 0..captures {
+        let split = policy.split(7, index, None);
+        counts[Split::ALL.iter().position(|s| *s == split).unwrap()] += 1;
+    }
+    counts.map(|count| count as f32 / captures as f32)
+}
+
+#[test]
+fn shares_follow_the_ratios() {
+    let [train, val, test] = shares(&policy(0.6, 0.3, 0.1, SplitKey::Block(1)), 20000);
+    assert!((train - 0.6).abs() < 0.02, "{} {} {}", train, val, test);
+    assert!((val - 0.3).abs() < 0.02, "{} {} {}", train, val, test);
+    assert!((test - 0.1).abs() < 0.02, "{} {} {}", train, val, test);
+}
+
+#[test]
+fn ratios_do_not_have_to_add_up_to_one() {
+    let [train, val, test] = shares(&policy(3.0, 1.0, 0.0, SplitKey::Block(1)), 20000);
+    assert!((train - 0.75).abs() < 0.02, "{} {} {}", train, val, test);
+    assert!((val - 0.25).abs() < 0.02, "{} {} {}", train, val, test);
+    assert_eq!(test, 0.0);
+
+    assert_eq!(shares(&policy(0.0, 0.0, 0.0, SplitKey::Block(1)), 100), [1.0, 0.0, 0.0]);
+    assert_eq!(shares(&policy(0.0, 0.0, 2.0, SplitKey::Block(1)), 100), [0.0, 0.0, 1.0]);
+}
+
+#[test]
+fn blocks_share_a_split() {
+    let policy = policy(0.5, 0.25, 0.25, SplitKey::Block(10));
+    let splits: Vec<Split> = (0..1000).map(|index| policy.split(7, index, None)).collect();
+
+    for block in splits.chunks(10) {
+        assert!(block.iter().all(|split| *split == block[0]), "{:?}", block);
+    }
+    // blocks are split independently of each other
+    assert!(splits.chunks(10).any(|block| block[0] != splits[0]));
+
+    // `Block(0)` is one capture per block
+    let zero = SplitPolicy { key: SplitKey::Block(0), ..policy.clone() };
+    let one = SplitPolicy { key: SplitKey::Block(1), ..policy };
+    assert!((0..100).all(|index| zero.split(7, index, None) == one.split(7, index, None)));
+}
+
+#[test]
+fn blocks_depend_on_the_seed() {
+    let policy = policy(0.5, 0.25, 0.25, SplitKey::Block(1));
+    let a: Vec<Split> = (0..100).map(|index| policy.split(1, index, None)).collect();
+    let b: Vec<Split> = (0..100).map(|index| policy.split(2, index, None)).collect();
+    assert_eq!(a, (0..100).map(|index| policy.split(1, index, None)).collect::<Vec<_>>());
+    assert_ne!(a, b);
+}
+
+#[test]
+fn scenes_land_in_one_split_in_any_run() {
+    let policy = policy(0.5, 0.25, 0.25, SplitKey::Scene);
+    let scenes = |scenes: Value| entries(json!({ "scenes": scenes }));
+
+    for name in ["boxes.gltf#Scene0", "street.gltf#Scene0", "room.scn.ron"] {
+        let entries = scenes(json!([name]));
+        let split = policy.split(1, 0, Some(&entries));
+        for (seed, index) in [(1, 1), (2, 0), (3, 500), (u64::MAX, u32::MAX)] {
+            assert_eq!(policy.split(seed, index, Some(&entries)), split, "{}", name);
+        }
+    }
+
+    let splits: Vec<Split> = (0..100)
+        .map(|scene| policy.split(1, 0, Some(&scenes(json!([format!("scene_{}.gltf", scene)])))))
+        .collect();
+    assert!(splits.iter().any(|split| *split != splits[0]));
+}
+
+#[test]
+fn frames_without_scenes_fall_back_to_blocks() {
+    let policy = policy(0.5, 0.25, 0.25, SplitKey::Scene);
+    let blocks = SplitPolicy { key: SplitKey::Block(100), ..policy.clone() };
+    let empty = entries(json!({ "scenes": [] }));
+
+    let splits: Vec<Split> = (0..2000).map(|index| policy.split(7, index, None)).collect();
+    for (index, split) in splits.iter().enumerate() {
+        assert_eq!(*split, blocks.split(7, index as u32, None));
+        assert_eq!(*split, policy.split(7, index as u32, Some(&empty)));
+    }
+    assert!(splits.iter().any(|split| *split != splits[0]));
+}
+
+#[test]
+fn seeds_and_parameters_share_a_split() {
+    let seed = policy(0.5, 0.25, 0.25, SplitKey::Seed);
+    for run in 0..20 {
+        let split = seed.split(run, 0, None);
+        assert!((0..100).all(|index| seed.split(run, index, None) == split));
+    }
+
+    let parameter = policy(0.5, 0.25, 0.25, SplitKey::Parameter("/pose/Car~1Mesh/rotation".to_string()));
+    let pose = |rotation: f32| entries(json!({ "pose": { "Car/Mesh": { "rotation": rotation } } }));
+    let split = parameter.split(1, 0, Some(&pose(0.5)));
+    assert_eq!(parameter.split(9, 42, Some(&pose(0.5))), split);
+
+    let splits: Vec<Split> = (0..100).map(|i| parameter.split(1, 0, Some(&pose(i as f32)))).collect();
+    assert!(splits.iter().any(|split| *split != splits[0]));
+}